@@ -1,15 +1,38 @@
-use std::env;
+use std::{env, str::from_utf8, time::Duration};
 
-use actix_web::{get, HttpRequest, Responder};
+use actix_rt::{task::JoinHandle, time::interval};
+use actix_web::{
+    dev::ServiceResponse,
+    get,
+    http::header::{self, Header},
+    middleware::ErrorHandlerResponse,
+    post, web, HttpRequest, HttpResponse, Responder,
+};
 use lazy_static::lazy_static;
+use mime::Mime;
 
-use crate::error::Error;
+use crate::{
+    error::{Error, PROBLEM_JSON},
+    git::ReusableRepoPool,
+    models,
+    query::{warm_up, QueryCache, WARM_UP_QUERIES, WARM_UP_TIMESTAMPS},
+    rdf::ConfiguredPrettifier,
+};
 
 lazy_static! {
     static ref API_KEY: String = env::var("API_KEY").unwrap_or_else(|e| {
         tracing::error!(error = e.to_string().as_str(), "API_KEY not found");
         std::process::exit(1)
     });
+    static ref PAYLOAD_MAX_BYTES: usize = match env::var("PAYLOAD_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes
+            .parse()
+            .unwrap_or_else(|e: std::num::ParseIntError| {
+                tracing::error!(error = e.to_string().as_str(), "invalid PAYLOAD_MAX_BYTES");
+                std::process::exit(1)
+            }),
+        Err(_) => 256 * 1024 * 1024,
+    };
 }
 
 #[get("/livez")]
@@ -37,3 +60,181 @@ pub fn validate_api_key(request: HttpRequest) -> Result<(), Error> {
         Err(Error::Unauthorized("incorrect api key".to_string()))
     }
 }
+
+/// Media types accepted by request, ordered by preference and leaving out those
+/// refused with q=0. Empty if request has no Accept header.
+pub fn accepted_media_types(request: &HttpRequest) -> Result<Vec<Mime>, Error> {
+    let accept = header::Accept::parse(request)
+        .map_err(|e| Error::BadRequest(format!("invalid accept header: {}", e)))?;
+    if accept.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let accepted = header::Accept(
+        accept
+            .iter()
+            .filter(|media_type| media_type.quality != header::Quality::ZERO)
+            .cloned()
+            .collect(),
+    );
+    if accepted.is_empty() {
+        return Err(Error::NotAcceptable(
+            "accept header refuses every media type".to_string(),
+        ));
+    }
+    Ok(accepted.ranked())
+}
+
+/// Limit request bodies to PAYLOAD_MAX_BYTES rather than the default of 256 KiB,
+/// which batches of graphs and namespace replacements easily exceed.
+pub fn payload_config() -> web::PayloadConfig {
+    web::PayloadConfig::new(*PAYLOAD_MAX_BYTES)
+}
+
+/// Reject requests whose path, query or JSON body can't be extracted as bad
+/// requests, so that they are answered with problem details like other errors, and
+/// limit request bodies with payload_config(). Use with `App::configure`.
+pub fn extractor_config(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::PathConfig::default().error_handler(|e, _| Error::BadRequest(e.to_string()).into()),
+    )
+    .app_data(
+        web::QueryConfig::default().error_handler(|e, _| Error::BadRequest(e.to_string()).into()),
+    )
+    .app_data(
+        web::JsonConfig::default().error_handler(|e, _| Error::BadRequest(e.to_string()).into()),
+    )
+    .app_data(payload_config());
+}
+
+/// Parse graphs from request body, either a JSON array or newline delimited JSON
+/// when content type is `application/x-ndjson`.
+pub fn parse_graphs(request: &HttpRequest, body: &[u8]) -> Result<Vec<models::Graph>, Error> {
+    let ndjson = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-ndjson"));
+
+    let body = from_utf8(body)?;
+    let graphs = if ndjson {
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?
+    } else {
+        serde_json::from_str(body)?
+    };
+    Ok(graphs)
+}
+
+/// Error handler that sets the instance of problem details to the request path.
+/// Other error responses, such as requests rejected by extractors, are turned into
+/// problem details from their status code. Use with
+/// `ErrorHandlers::new().default_handler(problem_instance)`.
+pub fn problem_instance<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let error = res.response().error();
+    let problem = match error.and_then(|e| e.as_error::<Error>()) {
+        Some(e) => e.problem(),
+        None => models::Error::from_status(res.status(), error),
+    };
+    let problem = models::Error {
+        instance: Some(res.request().path().to_string()),
+        ..problem
+    };
+
+    let status = res.status();
+    let (request, _) = res.into_parts();
+    let response = HttpResponse::build(status)
+        .content_type(PROBLEM_JSON)
+        .json(problem)
+        .map_into_right_body();
+    Ok(ErrorHandlerResponse::Response(ServiceResponse::new(
+        request, response,
+    )))
+}
+
+/// State shared by the handlers of the query cache.
+#[derive(Clone)]
+pub struct State {
+    pub cache: QueryCache,
+    pub rdf_prettifier: ConfiguredPrettifier,
+}
+
+/// Fetch updates to main immediately, for example when triggered by a push webhook,
+/// rather than waiting for the background fetch. Caches are keyed by commit, so
+/// cached entries stay valid and requests for the latest timestamp resolve to the
+/// new head without invalidating anything. Caches are warmed up for the new head in
+/// the background if main was updated.
+#[post("/api/refresh")]
+pub async fn post_api_refresh(
+    request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
+    state: web::Data<State>,
+) -> Result<impl Responder, Error> {
+    validate_api_key(request)?;
+
+    let updated = repos.fetch_updates().await?;
+    let head = repos
+        .get()
+        .await?
+        .refname_to_id("refs/heads/main")
+        .ok()
+        .map(|oid| oid.to_string());
+
+    if updated {
+        warm_up_in_background(repos, state);
+    }
+
+    Ok(HttpResponse::Ok().json(models::Refresh { updated, head }))
+}
+
+/// Fetch updates at the given interval, so that requests only read local state.
+/// Caches are warmed up at start and whenever main is updated.
+pub fn fetch_in_background(
+    repos: web::Data<ReusableRepoPool>,
+    state: web::Data<State>,
+    period: Duration,
+) -> JoinHandle<()> {
+    actix_rt::spawn(async move {
+        let mut interval = interval(period);
+        // Caches are cold at start.
+        let mut warm_up_needed = true;
+        loop {
+            interval.tick().await;
+
+            match repos.fetch_updates().await {
+                Ok(updated) => warm_up_needed |= updated,
+                Err(e) => tracing::error!(error = e.to_string(), "unable to fetch updates"),
+            }
+            if warm_up_needed {
+                match warm_up_latest(&repos, &state).await {
+                    Ok(()) => warm_up_needed = false,
+                    Err(e) => tracing::error!(error = e.to_string(), "unable to warm up cache"),
+                }
+            }
+        }
+    })
+}
+
+/// Warm up caches with a repo from the pool, without blocking the caller.
+fn warm_up_in_background(repos: web::Data<ReusableRepoPool>, state: web::Data<State>) {
+    actix_rt::spawn(async move {
+        if let Err(e) = warm_up_latest(&repos, &state).await {
+            tracing::error!(error = e.to_string(), "unable to warm up cache");
+        }
+    });
+}
+
+/// Warm up caches for the latest commit of main, and configured timestamps and queries.
+async fn warm_up_latest(repos: &ReusableRepoPool, state: &State) -> Result<(), Error> {
+    let repo = repos.get().await?;
+    warm_up(
+        &state.rdf_prettifier,
+        &repo,
+        &state.cache,
+        &WARM_UP_TIMESTAMPS,
+        &WARM_UP_QUERIES,
+    )
+    .await
+}
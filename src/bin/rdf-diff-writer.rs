@@ -5,29 +5,30 @@ use std::{str::from_utf8, time::Duration};
 
 use actix_rt::time::interval;
 use actix_web::{
-    delete, get, middleware::Logger, post, web, App, HttpRequest, HttpResponse, HttpServer,
-    Responder,
+    delete, get,
+    middleware::{ErrorHandlers, Logger},
+    post, put, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use lazy_static::lazy_static;
 use rdf_diff_store::{
-    api::{livez, readyz, validate_api_key},
+    api::{extractor_config, livez, parse_graphs, problem_instance, readyz, validate_api_key},
     error::Error,
     git::{
         checkout_main_and_fetch_updates, push_updates, ReusableRepoPool, GIT_REPOS_ROOT_PATH,
         GIT_REPO_URL,
     },
-    graphs::{delete_graph, store_graph},
+    graphs::{delete_graph, replace_graphs, store_graph, store_graphs},
     metrics::{get_metrics, middleware::HttpMetrics, register_metrics},
     models,
-    rdf::{APIPrettifier, RdfPrettifier},
+    rdf::{ConfiguredPrettifier, RdfPrettifier},
 };
 
 lazy_static! {
     // Only 1 repo (basically a lock) to avoid conflicting pushes to git storage.
-    static ref REPO_POOL: web::Data<async_lock::Mutex<ReusableRepoPool>> = web::Data::new(async_lock::Mutex::new(ReusableRepoPool::new(GIT_REPO_URL.clone(), GIT_REPOS_ROOT_PATH.clone(), 1).unwrap_or_else(|e| {
+    static ref REPO_POOL: web::Data<ReusableRepoPool> = web::Data::new(ReusableRepoPool::new(GIT_REPO_URL.clone(), GIT_REPOS_ROOT_PATH.clone(), 1).unwrap_or_else(|e| {
         tracing::error!(error = e.to_string().as_str(), "unable to create repo pool");
         std::process::exit(1)
-    })));
+    }));
 }
 
 #[get("/metrics")]
@@ -46,24 +47,68 @@ async fn metrics_endpoint() -> impl Responder {
 async fn post_api_graphs(
     request: HttpRequest,
     state: web::Data<State>,
-    repos: web::Data<async_lock::Mutex<ReusableRepoPool>>,
+    repos: web::Data<ReusableRepoPool>,
     body: web::Bytes,
 ) -> Result<impl Responder, Error> {
     validate_api_key(request)?;
 
     let graph: models::Graph = serde_json::from_str(from_utf8(&body)?)?;
 
-    let repo = ReusableRepoPool::pop(&repos).await;
+    let repo = repos.get().await?;
     checkout_main_and_fetch_updates(&repo)?;
-    let result = store_graph(&repo, &state.rdf_prettifier, &graph).await;
-    ReusableRepoPool::push(&repos, repo).await;
-
-    // Dont check result before pushing repo back into pool.
-    result?;
+    store_graph(&repo, &state.rdf_prettifier, &graph).await?;
 
     Ok(HttpResponse::Ok().message_body(""))
 }
 
+#[post("/api/graphs/batch")]
+async fn post_api_graphs_batch(
+    request: HttpRequest,
+    state: web::Data<State>,
+    repos: web::Data<ReusableRepoPool>,
+    body: web::Bytes,
+) -> Result<impl Responder, Error> {
+    validate_api_key(request.clone())?;
+    let graphs = parse_graphs(&request, &body)?;
+
+    let repo = repos.get().await?;
+    checkout_main_and_fetch_updates(&repo)?;
+    let statuses = store_graphs(&repo, &state.rdf_prettifier, &graphs).await?;
+
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplaceGraphsQueryParams {
+    namespace: String,
+}
+
+#[put("/api/graphs/replace")]
+async fn put_api_graphs_replace(
+    request: HttpRequest,
+    state: web::Data<State>,
+    repos: web::Data<ReusableRepoPool>,
+    query: web::Query<ReplaceGraphsQueryParams>,
+    body: web::Bytes,
+) -> Result<impl Responder, Error> {
+    validate_api_key(request.clone())?;
+    let graphs = parse_graphs(&request, &body)?;
+
+    let query_params = query.into_inner();
+
+    let repo = repos.get().await?;
+    checkout_main_and_fetch_updates(&repo)?;
+    let statuses = replace_graphs(
+        &repo,
+        &state.rdf_prettifier,
+        &query_params.namespace,
+        &graphs,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeleteGraphQueryParams {
     id: String,
@@ -72,27 +117,23 @@ pub struct DeleteGraphQueryParams {
 #[delete("/api/graphs")]
 async fn delete_api_graphs(
     request: HttpRequest,
-    repos: web::Data<async_lock::Mutex<ReusableRepoPool>>,
+    repos: web::Data<ReusableRepoPool>,
     query: web::Query<DeleteGraphQueryParams>,
 ) -> Result<impl Responder, Error> {
     validate_api_key(request)?;
 
     let query_params = query.into_inner();
 
-    let repo = ReusableRepoPool::pop(&repos).await;
+    let repo = repos.get().await?;
     checkout_main_and_fetch_updates(&repo)?;
-    let result = delete_graph(&repo, query_params.id).await;
-    ReusableRepoPool::push(&repos, repo).await;
-
-    // Dont check result before pushing repo back into pool.
-    result?;
+    delete_graph(&repo, query_params.id).await?;
 
     Ok(HttpResponse::Ok().message_body(""))
 }
 
 #[derive(Clone)]
 struct State {
-    rdf_prettifier: APIPrettifier,
+    rdf_prettifier: ConfiguredPrettifier,
 }
 
 #[actix_web::main]
@@ -114,16 +155,18 @@ async fn main() -> std::io::Result<()> {
         loop {
             interval.tick().await;
 
-            let repo = ReusableRepoPool::pop(&REPO_POOL).await;
-            if let Err(e) = push_updates(&repo) {
+            let result = match REPO_POOL.get().await {
+                Ok(repo) => push_updates(&repo),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
                 tracing::error!(error = e.to_string(), "unable to push updates");
             }
-            ReusableRepoPool::push(&REPO_POOL, repo).await;
         }
     });
 
     let state = State {
-        rdf_prettifier: APIPrettifier::new(),
+        rdf_prettifier: ConfiguredPrettifier::new(),
     };
 
     HttpServer::new(move || {
@@ -137,12 +180,16 @@ async fn main() -> std::io::Result<()> {
                     .log_target("http"),
             )
             .wrap(HttpMetrics)
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .configure(extractor_config)
             .app_data(web::Data::new(state.clone()))
             .app_data(web::Data::clone(&REPO_POOL))
             .service(livez)
             .service(readyz)
             .service(metrics_endpoint)
             .service(post_api_graphs)
+            .service(post_api_graphs_batch)
+            .service(put_api_graphs_replace)
             .service(delete_api_graphs)
     })
     .bind(("0.0.0.0", 8080))?
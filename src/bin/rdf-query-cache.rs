@@ -1,34 +1,57 @@
+use std::{env, str::from_utf8, time::Duration};
+
 use actix_web::http::header;
-use actix_web::{get, middleware::Logger, web, App, HttpResponse, HttpServer, Responder};
-use rdf_diff_store::api::{livez, readyz};
-use rdf_diff_store::git::{
-    checkout_main_and_fetch_updates, repo_metadata, ReusableRepoPool, GIT_REPOS_ROOT_PATH,
-    GIT_REPO_URL,
+use actix_web::{
+    get,
+    middleware::{ErrorHandlers, Logger},
+    post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use lazy_static::lazy_static;
+use rdf_diff_store::api::{
+    accepted_media_types, extractor_config, fetch_in_background, livez, post_api_refresh,
+    problem_instance, readyz, State,
 };
+use rdf_diff_store::git::{repo_metadata, ReusableRepoPool, GIT_REPOS_ROOT_PATH, GIT_REPO_URL};
+use rdf_diff_store::graphs::graph_history;
 use rdf_diff_store::metrics::middleware::CACHE_LEVEL_HEADER;
 use rdf_diff_store::metrics::{middleware::HttpMetrics, CACHE_COUNT};
 
-use rdf_diff_store::rdf::{APIPrettifier, RdfPrettifier};
+use rdf_diff_store::persistence::PersistentStores;
+use rdf_diff_store::rdf::{ConfiguredPrettifier, RdfPrettifier, SnapshotFormat};
 use rdf_diff_store::{
     error::Error,
     metrics::{get_metrics, register_metrics},
-    query::QueryCache,
-    query::{graphs_with_cache, query_with_cache},
+    query::{diff_with_cache, graph_at_timestamp, graph_diff, graphs_with_cache, query_with_cache},
+    query::{QueryCache, ResultFormats},
 };
 use serde::Deserialize;
 
+lazy_static! {
+    static ref FETCH_INTERVAL: Duration = match env::var("FETCH_INTERVAL_SECONDS") {
+        Ok(seconds) => Duration::from_secs(seconds.parse().unwrap_or_else(
+            |e: std::num::ParseIntError| {
+                tracing::error!(
+                    error = e.to_string().as_str(),
+                    "invalid FETCH_INTERVAL_SECONDS"
+                );
+                std::process::exit(1)
+            }
+        )),
+        Err(_) => Duration::from_secs(10),
+    };
+}
+
 #[get("/metrics")]
 async fn metrics_endpoint(state: web::Data<State>) -> impl Responder {
-    // Update number of items in caches for each metric request.
-    CACHE_COUNT
-        .with_label_values(&["graphs"])
-        .set(state.cache.graphs_cache.entry_count() as i64);
-    CACHE_COUNT
-        .with_label_values(&["queries"])
-        .set(state.cache.query_cache.entry_count() as i64);
-    CACHE_COUNT
-        .with_label_values(&["stores"])
-        .set(state.cache.store_cache.entry_count() as i64);
+    // Update number and size of items in caches for each metric request.
+    for (cache_type, count, size) in state.cache.sizes() {
+        CACHE_COUNT
+            .with_label_values(&[cache_type, "items"])
+            .set(count as i64);
+        CACHE_COUNT
+            .with_label_values(&[cache_type, "bytes"])
+            .set(size as i64);
+    }
 
     match get_metrics() {
         Ok(metrics) => metrics,
@@ -47,80 +70,200 @@ pub struct SparqlQueryParams {
 
 #[get("/api/sparql/{timestamp}")]
 async fn get_api_sparql(
-    //request: HttpRequest,
-    repos: web::Data<async_lock::Mutex<ReusableRepoPool>>,
+    request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
     path: web::Path<u64>,
     query: web::Query<SparqlQueryParams>,
     state: web::Data<State>,
 ) -> Result<impl Responder, Error> {
-    // validate_api_key(request)?;
-
     let timestamp = path.into_inner();
     let query_params = query.into_inner();
 
-    let repo = ReusableRepoPool::pop(&repos).await;
-    checkout_main_and_fetch_updates(&repo)?;
-    let result = query_with_cache(
+    sparql(&request, &repos, &state, timestamp, query_params.query).await
+}
+
+/// SPARQL 1.1 Protocol query via POST, either directly with `application/sparql-query`
+/// or URL-encoded with `application/x-www-form-urlencoded`.
+#[post("/api/sparql/{timestamp}")]
+async fn post_api_sparql(
+    request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
+    path: web::Path<u64>,
+    body: web::Bytes,
+    state: web::Data<State>,
+) -> Result<impl Responder, Error> {
+    let timestamp = path.into_inner();
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+
+    let query = match content_type {
+        Some("application/sparql-query") => from_utf8(&body)?.to_string(),
+        Some("application/x-www-form-urlencoded") => {
+            web::Query::<SparqlQueryParams>::from_query(from_utf8(&body)?)
+                .map_err(|e| Error::BadRequest(e.to_string()))?
+                .into_inner()
+                .query
+        }
+        _ => return Err(Error::UnsupportedMediaType(
+            "content type must be application/sparql-query or application/x-www-form-urlencoded"
+                .to_string(),
+        )),
+    };
+
+    sparql(&request, &repos, &state, timestamp, query).await
+}
+
+/// Query timestamp, with results in a format negotiated from Accept header.
+async fn sparql(
+    request: &HttpRequest,
+    repos: &web::Data<ReusableRepoPool>,
+    state: &State,
+    timestamp: u64,
+    query: String,
+) -> Result<HttpResponse, Error> {
+    let media_types = accepted_media_types(request)?;
+    let formats = ResultFormats::from_media_types(
+        media_types
+            .iter()
+            .map(|media_type| media_type.essence_str()),
+    );
+
+    let repo = repos.get().await?;
+    let (query_result, cache_lvl) = query_with_cache(
         &state.rdf_prettifier,
         &repo,
         &state.cache,
         timestamp,
-        query_params.query,
+        query,
+        formats,
     )
-    .await;
-    ReusableRepoPool::push(&repos, repo).await;
-
-    // Dont check result before pushing repo back into pool.
-    let (body, cache_lvl) = result?;
+    .await?;
     Ok(HttpResponse::Ok()
-        .insert_header((header::CONTENT_TYPE, mime::APPLICATION_JSON))
+        .insert_header((header::CONTENT_TYPE, query_result.media_type))
         .insert_header((CACHE_LEVEL_HEADER, cache_lvl.to_string()))
-        .message_body(body))
+        .body(query_result.body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GraphsQueryParams {
+    id: Option<String>,
 }
 
 #[get("/api/graphs/{timestamp}")]
 async fn get_api_graphs(
-    //request: HttpRequest,
-    repos: web::Data<async_lock::Mutex<ReusableRepoPool>>,
+    request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
     path: web::Path<u64>,
+    query: web::Query<GraphsQueryParams>,
     state: web::Data<State>,
 ) -> Result<impl Responder, Error> {
     //validate_api_key(request)?;
 
     let timestamp = path.into_inner();
+    let query_params = query.into_inner();
 
-    let repo = ReusableRepoPool::pop(&repos).await;
-    checkout_main_and_fetch_updates(&repo)?;
-    let result = graphs_with_cache(&state.rdf_prettifier, &repo, &state.cache, timestamp).await;
-    ReusableRepoPool::push(&repos, repo).await;
+    let media_types = accepted_media_types(&request)?;
+    let format = SnapshotFormat::from_media_types(
+        media_types
+            .iter()
+            .map(|media_type| media_type.essence_str()),
+    )
+    .ok_or_else(|| Error::NotAcceptable("no supported graph format is acceptable".to_string()))?;
 
-    // Dont check result before pushing repo back into pool.
-    let (body, cache_lvl) = result?;
+    let repo = repos.get().await?;
+    let (body, cache_lvl) = match query_params.id {
+        Some(id) => graph_at_timestamp(&repo, &id, timestamp, format),
+        None => {
+            graphs_with_cache(
+                &state.rdf_prettifier,
+                &repo,
+                &state.cache,
+                timestamp,
+                format,
+            )
+            .await
+        }
+    }?;
     Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, format.media_type()))
         .insert_header((CACHE_LEVEL_HEADER, cache_lvl.to_string()))
         .message_body(body))
 }
 
-#[get("/api/metadata")]
-async fn get_api_metadata(
+#[derive(Debug, Deserialize)]
+pub struct DiffQueryParams {
+    from: u64,
+    to: u64,
+}
+
+#[get("/api/diff")]
+async fn get_api_diff(
     //request: HttpRequest,
-    repos: web::Data<async_lock::Mutex<ReusableRepoPool>>,
+    repos: web::Data<ReusableRepoPool>,
+    query: web::Query<DiffQueryParams>,
+    state: web::Data<State>,
 ) -> Result<impl Responder, Error> {
     //validate_api_key(request)?;
 
-    let repo = ReusableRepoPool::pop(&repos).await;
-    checkout_main_and_fetch_updates(&repo)?;
-    let result = repo_metadata(&repo).await;
-    ReusableRepoPool::push(&repos, repo).await;
+    let query_params = query.into_inner();
 
-    // Dont check result before pushing repo back into pool.
-    Ok(HttpResponse::Ok().json(result?))
+    let repo = repos.get().await?;
+    let (diff, cache_lvl) =
+        diff_with_cache(&repo, &state.cache, query_params.from, query_params.to).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_LEVEL_HEADER, cache_lvl.to_string()))
+        .json(diff))
 }
 
-#[derive(Clone)]
-struct State {
-    cache: QueryCache,
-    rdf_prettifier: APIPrettifier,
+#[get("/api/graphs/{id}/diff")]
+async fn get_api_graph_diff(
+    //request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
+    path: web::Path<String>,
+    query: web::Query<DiffQueryParams>,
+) -> Result<impl Responder, Error> {
+    //validate_api_key(request)?;
+
+    let id = path.into_inner();
+    let query_params = query.into_inner();
+
+    let repo = repos.get().await?;
+    let diff = graph_diff(&repo, &id, query_params.from, query_params.to)?;
+
+    Ok(HttpResponse::Ok().json(diff))
+}
+
+#[get("/api/graphs/{id}/history")]
+async fn get_api_graph_history(
+    //request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
+    path: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    //validate_api_key(request)?;
+
+    let id = path.into_inner();
+
+    let repo = repos.get().await?;
+    let history = graph_history(&repo, &id)?;
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[get("/api/metadata")]
+async fn get_api_metadata(
+    //request: HttpRequest,
+    repos: web::Data<ReusableRepoPool>,
+) -> Result<impl Responder, Error> {
+    //validate_api_key(request)?;
+
+    let repo = repos.get().await?;
+    let metadata = repo_metadata(&repo).await?;
+
+    Ok(HttpResponse::Ok().json(metadata))
 }
 
 #[actix_web::main]
@@ -139,13 +282,31 @@ async fn main() -> std::io::Result<()> {
             tracing::error!(error = e.to_string().as_str(), "unable to create repo pool");
             std::process::exit(1)
         });
-    let repo_pool = web::Data::new(async_lock::Mutex::new(repo_pool));
+    let repo_pool = web::Data::new(repo_pool);
 
-    let state = State {
-        cache: QueryCache::new(),
-        rdf_prettifier: APIPrettifier::new(),
+    let cache = match PersistentStores::from_env() {
+        Some(persistent_stores) => QueryCache::with_persistent_stores(persistent_stores)
+            .unwrap_or_else(|e| {
+                tracing::error!(
+                    error = e.to_string().as_str(),
+                    "unable to open persistent stores"
+                );
+                std::process::exit(1)
+            }),
+        None => QueryCache::new(),
     };
 
+    let state = web::Data::new(State {
+        cache,
+        rdf_prettifier: ConfiguredPrettifier::new(),
+    });
+
+    fetch_in_background(
+        web::Data::clone(&repo_pool),
+        web::Data::clone(&state),
+        *FETCH_INTERVAL,
+    );
+
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -157,14 +318,21 @@ async fn main() -> std::io::Result<()> {
                     .log_target("http"),
             )
             .wrap(HttpMetrics)
-            .app_data(web::Data::new(state.clone()))
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .configure(extractor_config)
+            .app_data(web::Data::clone(&state))
             .app_data(web::Data::clone(&repo_pool))
             .service(livez)
             .service(readyz)
             .service(metrics_endpoint)
             .service(get_api_sparql)
+            .service(post_api_sparql)
             .service(get_api_graphs)
+            .service(get_api_diff)
+            .service(get_api_graph_diff)
+            .service(get_api_graph_history)
             .service(get_api_metadata)
+            .service(post_api_refresh)
     })
     .bind(("0.0.0.0", 8081))?
     .workers(32)
@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use oxigraph::model::{
+    vocab::rdf, BlankNode, Literal, NamedNode, NamedNodeRef, Subject, Term, Triple,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+/// Prefixes used when serializing canonical Turtle. Only prefixes that are
+/// used are declared, but a namespace is always abbreviated with the same prefix.
+const PREFIXES: &[(&str, &str)] = &[
+    ("adms", "http://www.w3.org/ns/adms#"),
+    ("cpsv", "http://purl.org/vocab/cpsv#"),
+    ("cv", "http://data.europa.eu/m8g/"),
+    ("dcat", "http://www.w3.org/ns/dcat#"),
+    ("dcatap", "http://data.europa.eu/r5r/"),
+    ("dcatno", "https://data.norge.no/vocabulary/dcatno#"),
+    ("dct", "http://purl.org/dc/terms/"),
+    ("dqv", "http://www.w3.org/ns/dqv#"),
+    ("eli", "http://data.europa.eu/eli/ontology#"),
+    ("euvoc", "http://publications.europa.eu/ontology/euvoc#"),
+    ("foaf", "http://xmlns.com/foaf/0.1/"),
+    ("locn", "http://www.w3.org/ns/locn#"),
+    ("odrl", "http://www.w3.org/ns/odrl/2/"),
+    ("owl", "http://www.w3.org/2002/07/owl#"),
+    ("prov", "http://www.w3.org/ns/prov#"),
+    ("rdf", "http://www.w3.org/1999/02/22-rdf-syntax-ns#"),
+    ("rdfs", "http://www.w3.org/2000/01/rdf-schema#"),
+    ("schema", "http://schema.org/"),
+    ("skos", "http://www.w3.org/2004/02/skos/core#"),
+    ("skosno", "https://data.norge.no/vocabulary/skosno#"),
+    ("skosxl", "http://www.w3.org/2008/05/skos-xl#"),
+    ("spdx", "http://spdx.org/rdf/terms#"),
+    ("time", "http://www.w3.org/2006/time#"),
+    ("vcard", "http://www.w3.org/2006/vcard/ns#"),
+    ("xkos", "http://rdf-vocabulary.ddialliance.org/xkos#"),
+    ("xsd", "http://www.w3.org/2001/XMLSchema#"),
+];
+
+/// Serialize triples as Turtle with sorted triples, stable prefixes and
+/// canonical blank node labels, so that equal graphs are always written
+/// identically. Blank nodes are labelled `{blank_node_prefix}{n}`.
+pub fn canonical_turtle(triples: Vec<Triple>, blank_node_prefix: &str) -> Result<String, Error> {
+    let mut triples = canonicalize_blank_nodes(triples, blank_node_prefix)?
+        .into_iter()
+        .map(|triple| {
+            let key = (
+                triple.subject.to_string(),
+                predicate_sort_key(triple.predicate.as_ref()),
+                triple.object.to_string(),
+            );
+            (key, triple)
+        })
+        .collect::<Vec<_>>();
+    triples.sort_by(|(a, _), (b, _)| a.cmp(b));
+    triples.dedup_by(|(a, _), (b, _)| a == b);
+
+    let mut used_prefixes = BTreeSet::new();
+    let mut body = String::new();
+
+    let mut previous: Option<&Triple> = None;
+    for (_, triple) in triples.iter() {
+        match previous {
+            Some(prev) if prev.subject == triple.subject && prev.predicate == triple.predicate => {
+                body.push_str(",\n        ");
+            }
+            Some(prev) if prev.subject == triple.subject => {
+                body.push_str(" ;\n    ");
+                body.push_str(&predicate(triple.predicate.as_ref(), &mut used_prefixes));
+                body.push(' ');
+            }
+            prev => {
+                if prev.is_some() {
+                    body.push_str(" .\n\n");
+                }
+                body.push_str(&subject(&triple.subject, &mut used_prefixes));
+                body.push(' ');
+                body.push_str(&predicate(triple.predicate.as_ref(), &mut used_prefixes));
+                body.push(' ');
+            }
+        }
+        body.push_str(&object(&triple.object, &mut used_prefixes));
+        previous = Some(triple);
+    }
+    if previous.is_some() {
+        body.push_str(" .\n");
+    }
+
+    let mut turtle = String::new();
+    for (prefix, namespace) in PREFIXES {
+        if used_prefixes.contains(prefix) {
+            turtle.push_str(&format!("@prefix {}: <{}> .\n", prefix, namespace));
+        }
+    }
+    if !used_prefixes.is_empty() {
+        turtle.push('\n');
+    }
+    turtle.push_str(&body);
+    Ok(turtle)
+}
+
+/// Maximum number of steps spent canonicalizing one graph, counting n-degree hashes
+/// and permutations of related blank nodes, to stop graphs with many
+/// indistinguishable blank nodes from exhausting resources.
+const MAX_STEPS: usize = 100_000;
+
+/// Relabel blank nodes deterministically, following RDF Dataset
+/// Canonicalization (RDFC-1.0) with SHA-256. Isomorphic graphs get identical
+/// labels, independent of their original blank node labels.
+/// Canonical labels are `{prefix}{n}`. Fails if the graph can't be canonicalized
+/// within MAX_STEPS.
+pub fn canonicalize_blank_nodes(triples: Vec<Triple>, prefix: &str) -> Result<Vec<Triple>, Error> {
+    let mut mentions: HashMap<BlankNode, Vec<&Triple>> = HashMap::new();
+    for triple in triples.iter() {
+        if let Subject::BlankNode(node) = &triple.subject {
+            mentions.entry(node.clone()).or_default().push(triple);
+        }
+        if let Term::BlankNode(node) = &triple.object {
+            if triple.subject != Subject::BlankNode(node.clone()) {
+                mentions.entry(node.clone()).or_default().push(triple);
+            }
+        }
+    }
+    if mentions.is_empty() {
+        return Ok(triples);
+    }
+
+    let labels = Canonicalizer::new(&mentions, prefix).labels()?;
+
+    Ok(triples
+        .into_iter()
+        .map(|triple| {
+            let subject = match triple.subject {
+                Subject::BlankNode(node) => Subject::BlankNode(labels.canonical(&node)),
+                subject => subject,
+            };
+            let object = match triple.object {
+                Term::BlankNode(node) => Term::BlankNode(labels.canonical(&node)),
+                object => object,
+            };
+            Triple::new(subject, triple.predicate, object)
+        })
+        .collect())
+}
+
+/// Issues identifiers to blank nodes, in order of first issuance.
+#[derive(Clone)]
+struct IdentifierIssuer {
+    prefix: String,
+    issued: Vec<BlankNode>,
+    identifiers: HashMap<BlankNode, String>,
+}
+
+impl IdentifierIssuer {
+    fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            issued: Vec::new(),
+            identifiers: HashMap::new(),
+        }
+    }
+
+    fn issue(&mut self, node: &BlankNode) -> String {
+        if let Some(identifier) = self.identifiers.get(node) {
+            return identifier.clone();
+        }
+        let identifier = format!("{}{}", self.prefix, self.issued.len());
+        self.issued.push(node.clone());
+        self.identifiers.insert(node.clone(), identifier.clone());
+        identifier
+    }
+
+    fn get(&self, node: &BlankNode) -> Option<&String> {
+        self.identifiers.get(node)
+    }
+
+    fn canonical(&self, node: &BlankNode) -> BlankNode {
+        BlankNode::new_unchecked(self.identifiers[node].clone())
+    }
+}
+
+struct Canonicalizer<'a> {
+    mentions: &'a HashMap<BlankNode, Vec<&'a Triple>>,
+    canonical_issuer: IdentifierIssuer,
+    steps: usize,
+}
+
+impl<'a> Canonicalizer<'a> {
+    fn new(mentions: &'a HashMap<BlankNode, Vec<&'a Triple>>, prefix: &str) -> Self {
+        Self {
+            mentions,
+            canonical_issuer: IdentifierIssuer::new(prefix),
+            steps: 0,
+        }
+    }
+
+    /// Count a step of work, failing once MAX_STEPS is exceeded.
+    fn step(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(Error::Canonicalization(
+                "graph has too many indistinguishable blank nodes".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Issue canonical labels to every blank node.
+    fn labels(mut self) -> Result<IdentifierIssuer, Error> {
+        let mut hash_to_nodes: BTreeMap<String, Vec<BlankNode>> = BTreeMap::new();
+        for node in self.mentions.keys() {
+            hash_to_nodes
+                .entry(self.first_degree_hash(node))
+                .or_default()
+                .push(node.clone());
+        }
+
+        // Nodes with a unique first degree hash are labelled in hash order.
+        for nodes in hash_to_nodes.values() {
+            if let [node] = nodes.as_slice() {
+                self.canonical_issuer.issue(node);
+            }
+        }
+
+        // Remaining nodes are distinguished by the blank nodes they are related to.
+        for nodes in hash_to_nodes.values().filter(|nodes| nodes.len() > 1) {
+            let mut hash_paths = Vec::new();
+            for node in nodes {
+                if self.canonical_issuer.get(node).is_some() {
+                    continue;
+                }
+                let mut issuer = IdentifierIssuer::new("b");
+                issuer.issue(node);
+                hash_paths.push(self.n_degree_hash(node, issuer)?);
+            }
+            hash_paths.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (_, issuer) in hash_paths {
+                for node in issuer.issued.iter() {
+                    self.canonical_issuer.issue(node);
+                }
+            }
+        }
+
+        Ok(self.canonical_issuer)
+    }
+
+    /// Hash of the sorted triples a blank node appears in, where the node itself is
+    /// written as `_:a` and any other blank node as `_:z`.
+    fn first_degree_hash(&self, node: &BlankNode) -> String {
+        let placeholder = |other: &BlankNode| {
+            if other == node {
+                "_:a".to_string()
+            } else {
+                "_:z".to_string()
+            }
+        };
+
+        let mut lines = self.mentions[node]
+            .iter()
+            .map(|triple| {
+                let subject = match &triple.subject {
+                    Subject::BlankNode(other) => placeholder(other),
+                    subject => subject.to_string(),
+                };
+                let object = match &triple.object {
+                    Term::BlankNode(other) => placeholder(other),
+                    object => object.to_string(),
+                };
+                format!("{} {} {} .\n", subject, triple.predicate, object)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        sha256(&lines.concat())
+    }
+
+    /// Hash of a blank node related to another through a triple, at position `s` or `o`.
+    fn related_hash(
+        &self,
+        related: &BlankNode,
+        triple: &Triple,
+        issuer: &IdentifierIssuer,
+        position: char,
+    ) -> String {
+        let identifier = match self
+            .canonical_issuer
+            .get(related)
+            .or_else(|| issuer.get(related))
+        {
+            Some(identifier) => format!("_:{}", identifier),
+            None => self.first_degree_hash(related),
+        };
+        sha256(&format!("{}{}{}", position, triple.predicate, identifier))
+    }
+
+    /// Hash of a blank node and the paths to every blank node it is related to,
+    /// alongside the issuer used for the chosen paths.
+    fn n_degree_hash(
+        &mut self,
+        node: &BlankNode,
+        mut issuer: IdentifierIssuer,
+    ) -> Result<(String, IdentifierIssuer), Error> {
+        self.step()?;
+
+        let mut hash_to_related: BTreeMap<String, Vec<BlankNode>> = BTreeMap::new();
+        for triple in self.mentions[node].iter() {
+            if let Subject::BlankNode(related) = &triple.subject {
+                if related != node {
+                    let hash = self.related_hash(related, triple, &issuer, 's');
+                    hash_to_related
+                        .entry(hash)
+                        .or_default()
+                        .push(related.clone());
+                }
+            }
+            if let Term::BlankNode(related) = &triple.object {
+                if related != node {
+                    let hash = self.related_hash(related, triple, &issuer, 'o');
+                    hash_to_related
+                        .entry(hash)
+                        .or_default()
+                        .push(related.clone());
+                }
+            }
+        }
+
+        let mut data_to_hash = String::new();
+        for (related_hash, related) in hash_to_related {
+            data_to_hash.push_str(&related_hash);
+
+            let mut chosen: Option<(String, IdentifierIssuer)> = None;
+            'permutations: for permutation in Permutations::new(related) {
+                self.step()?;
+                let mut issuer_copy = issuer.clone();
+                let mut path = String::new();
+                let mut recursion_list = Vec::new();
+
+                for related in permutation.iter() {
+                    match self.canonical_issuer.get(related) {
+                        Some(identifier) => path.push_str(&format!("_:{}", identifier)),
+                        None => {
+                            if issuer_copy.get(related).is_none() {
+                                recursion_list.push(related.clone());
+                            }
+                            path.push_str(&format!("_:{}", issuer_copy.issue(related)));
+                        }
+                    }
+                    if is_worse_path(&path, &chosen) {
+                        continue 'permutations;
+                    }
+                }
+
+                for related in recursion_list {
+                    let (hash, result_issuer) =
+                        self.n_degree_hash(&related, issuer_copy.clone())?;
+                    issuer_copy = result_issuer;
+                    path.push_str(&format!("_:{}<{}>", issuer_copy.issue(&related), hash));
+                    if is_worse_path(&path, &chosen) {
+                        continue 'permutations;
+                    }
+                }
+
+                if !matches!(&chosen, Some((chosen_path, _)) if &path >= chosen_path) {
+                    chosen = Some((path, issuer_copy));
+                }
+            }
+
+            if let Some((chosen_path, chosen_issuer)) = chosen {
+                data_to_hash.push_str(&chosen_path);
+                issuer = chosen_issuer;
+            }
+        }
+
+        Ok((sha256(&data_to_hash), issuer))
+    }
+}
+
+/// Whether path can no longer become shorter or lexicographically smaller than chosen path.
+fn is_worse_path(path: &str, chosen: &Option<(String, IdentifierIssuer)>) -> bool {
+    match chosen {
+        Some((chosen_path, _)) => path.len() >= chosen_path.len() && path > chosen_path.as_str(),
+        None => false,
+    }
+}
+
+/// Every permutation of nodes, generated one at a time in lexicographic order of
+/// their positions, as there are n! of them.
+struct Permutations {
+    nodes: Vec<BlankNode>,
+    indices: Option<Vec<usize>>,
+}
+
+impl Permutations {
+    fn new(nodes: Vec<BlankNode>) -> Self {
+        Self {
+            indices: Some((0..nodes.len()).collect()),
+            nodes,
+        }
+    }
+}
+
+impl Iterator for Permutations {
+    type Item = Vec<BlankNode>;
+
+    fn next(&mut self) -> Option<Vec<BlankNode>> {
+        let indices = self.indices.as_mut()?;
+        let permutation = indices.iter().map(|i| self.nodes[*i].clone()).collect();
+        if !next_permutation(indices) {
+            self.indices = None;
+        }
+        Some(permutation)
+    }
+}
+
+/// Rearrange indices into the next permutation in lexicographic order. Returns false
+/// if they already were the last permutation.
+fn next_permutation(indices: &mut [usize]) -> bool {
+    let pivot = match indices.windows(2).rposition(|pair| pair[0] < pair[1]) {
+        Some(pivot) => pivot,
+        None => return false,
+    };
+    let successor = indices
+        .iter()
+        .rposition(|index| *index > indices[pivot])
+        .unwrap_or(pivot + 1);
+    indices.swap(pivot, successor);
+    indices[pivot + 1..].reverse();
+    true
+}
+
+fn sha256(data: &str) -> String {
+    format!("{:x}", Sha256::digest(data.as_bytes()))
+}
+
+/// Sort rdf:type before any other predicate, as it is written as `a`.
+fn predicate_sort_key(predicate: NamedNodeRef) -> String {
+    if predicate == rdf::TYPE {
+        String::new()
+    } else {
+        predicate.to_string()
+    }
+}
+
+fn subject(subject: &Subject, used_prefixes: &mut BTreeSet<&'static str>) -> String {
+    match subject {
+        Subject::NamedNode(node) => named_node(node.as_ref(), used_prefixes),
+        subject => subject.to_string(),
+    }
+}
+
+fn predicate(predicate: NamedNodeRef, used_prefixes: &mut BTreeSet<&'static str>) -> String {
+    if predicate == rdf::TYPE {
+        "a".to_string()
+    } else {
+        named_node(predicate, used_prefixes)
+    }
+}
+
+fn object(object: &Term, used_prefixes: &mut BTreeSet<&'static str>) -> String {
+    match object {
+        Term::NamedNode(node) => named_node(node.as_ref(), used_prefixes),
+        Term::Literal(literal) => self::literal(literal, used_prefixes),
+        object => object.to_string(),
+    }
+}
+
+fn literal(literal: &Literal, used_prefixes: &mut BTreeSet<&'static str>) -> String {
+    if literal.is_plain() {
+        return literal.to_string();
+    }
+    let value = Literal::new_simple_literal(literal.value()).to_string();
+    let datatype = NamedNode::from(literal.datatype());
+    format!(
+        "{}^^{}",
+        value,
+        named_node(datatype.as_ref(), used_prefixes)
+    )
+}
+
+/// Abbreviate IRI with a prefix when possible.
+fn named_node(node: NamedNodeRef, used_prefixes: &mut BTreeSet<&'static str>) -> String {
+    for (prefix, namespace) in PREFIXES {
+        if let Some(local) = node.as_str().strip_prefix(namespace) {
+            if is_simple_local_name(local) {
+                used_prefixes.insert(prefix);
+                return format!("{}:{}", prefix, local);
+            }
+        }
+    }
+    node.to_string()
+}
+
+/// Local names that are safe to use in prefixed names without escaping.
+fn is_simple_local_name(local: &str) -> bool {
+    let mut chars = local.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        }
+        _ => false,
+    }
+}
@@ -1,6 +1,6 @@
-use std::io;
+use std::{error::Error as _, io};
 
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 
 use crate::models;
 
@@ -10,6 +10,20 @@ pub enum Error {
     String(String),
     #[error("unauthorized: {0}")]
     Unauthorized(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("not acceptable: {0}")]
+    NotAcceptable(String),
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("service unavailable: {0}")]
+    ServiceUnavailable(String),
+    #[error("unable to canonicalize graph: {0}")]
+    Canonicalization(String),
     #[error(transparent)]
     Utf8Error(#[from] std::str::Utf8Error),
     #[error(transparent)]
@@ -19,6 +33,8 @@ pub enum Error {
     #[error(transparent)]
     StorageError(#[from] oxigraph::store::StorageError),
     #[error(transparent)]
+    ParseError(#[from] oxigraph::io::read::ParseError),
+    #[error(transparent)]
     LoaderError(#[from] oxigraph::store::LoaderError),
     #[error(transparent)]
     SerializerError(#[from] oxigraph::store::SerializerError),
@@ -46,14 +62,118 @@ impl From<String> for Error {
     }
 }
 
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+impl Error {
+    /// Machine-readable code identifying the kind of error.
+    pub fn code(&self) -> &'static str {
+        use oxigraph::sparql::EvaluationError as Evaluation;
+        use Error::*;
+
+        match self {
+            Unauthorized(_) => "unauthorized",
+            NotFound(_) => "not_found",
+            BadRequest(_) => "bad_request",
+            Conflict(_) => "conflict",
+            NotAcceptable(_) => "not_acceptable",
+            UnsupportedMediaType(_) => "unsupported_media_type",
+            ServiceUnavailable(_) => "service_unavailable",
+            Utf8Error(_) => "invalid_utf8",
+            SerdeJsonError(_) => "invalid_json",
+            EvaluationError(Evaluation::Parsing(_)) => "invalid_query",
+            _ => "internal_error",
+        }
+    }
+
+    /// Short human-readable summary of the kind of error.
+    fn title(&self) -> &'static str {
+        match self.code() {
+            "unauthorized" => "Unauthorized",
+            "not_found" => "Not found",
+            "bad_request" => "Bad request",
+            "conflict" => "Conflict",
+            "not_acceptable" => "Not acceptable",
+            "unsupported_media_type" => "Unsupported media type",
+            "service_unavailable" => "Service unavailable",
+            "invalid_utf8" => "Invalid UTF-8",
+            "invalid_json" => "Invalid JSON",
+            "invalid_query" => "Invalid query",
+            _ => "Internal error",
+        }
+    }
+
+    /// Position of the syntax error in a SPARQL query, if the error is one. The
+    /// position is only available when the peg dependency is the same version as
+    /// the one spargebra parses queries with, which the problem_details test checks.
+    fn query_position(&self) -> Option<models::ErrorPosition> {
+        match self {
+            Error::EvaluationError(oxigraph::sparql::EvaluationError::Parsing(e)) => e
+                .source()
+                .and_then(|e| e.downcast_ref::<peg::error::ParseError<peg::str::LineCol>>())
+                .map(|e| {
+                    models::ErrorPosition::new(
+                        e.location.line as i64,
+                        e.location.column as i64,
+                        e.location.offset as i64,
+                    )
+                }),
+            _ => None,
+        }
+    }
+
+    /// RFC 7807 problem details of error, without instance.
+    pub fn problem(&self) -> models::Error {
+        let status = self.status_code();
+        let body = if status.is_client_error() {
+            models::Error::message(self)
+        } else {
+            models::Error::error(self)
+        };
+
+        models::Error {
+            r#type: Some(format!("urn:rdf-diff-store:problem:{}", self.code())),
+            title: Some(self.title().to_string()),
+            status: Some(status.as_u16() as i32),
+            detail: Some(self.to_string()),
+            code: Some(self.code().to_string()),
+            position: self.query_position().map(Box::new),
+            ..body
+        }
+    }
+}
+
 impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        use oxigraph::sparql::EvaluationError as Evaluation;
+        use Error::*;
+
+        match self {
+            Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            NotFound(_) => StatusCode::NOT_FOUND,
+            BadRequest(_)
+            | Utf8Error(_)
+            | SerdeJsonError(_)
+            | EvaluationError(Evaluation::Parsing(_)) => StatusCode::BAD_REQUEST,
+            Conflict(_) => StatusCode::CONFLICT,
+            NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
         use Error::*;
 
+        let status = self.status_code();
         match self {
             GitError(e) => {
                 tracing::error!(error = e.to_string().as_str(), "git error occured")
             }
+            // Client errors are expected, and not a problem with the service.
+            e if status.is_client_error() => {
+                tracing::info!(error = e.to_string().as_str(), "Invalid request")
+            }
             e => {
                 tracing::warn!(
                     error = e.to_string().as_str(),
@@ -62,20 +182,43 @@ impl ResponseError for Error {
             }
         };
 
-        match self {
-            Unauthorized(_) => HttpResponse::Unauthorized().json(models::Error::message(self)),
-            _ => HttpResponse::InternalServerError().json(models::Error::error(self)),
-        }
+        HttpResponse::build(status)
+            .content_type(PROBLEM_JSON)
+            .json(self.problem())
     }
 }
 
 impl models::Error {
+    /// RFC 7807 problem details of an error response that is not from an Error, such
+    /// as a request rejected by an extractor, derived from its status code.
+    pub fn from_status<S: ToString>(status: StatusCode, detail: Option<S>) -> Self {
+        let reason = status.canonical_reason().unwrap_or("Unknown error");
+        let code = reason.to_lowercase().replace(' ', "_");
+        let title = format!("{}{}", &reason[..1], reason[1..].to_lowercase());
+        let detail = detail.map(|detail| detail.to_string());
+        let body = match &detail {
+            Some(detail) if status.is_client_error() => models::Error::message(detail),
+            Some(detail) => models::Error::error(detail),
+            None => models::Error::default(),
+        };
+
+        models::Error {
+            r#type: Some(format!("urn:rdf-diff-store:problem:{}", code)),
+            title: Some(title),
+            status: Some(status.as_u16() as i32),
+            detail,
+            code: Some(code),
+            ..body
+        }
+    }
+
     fn message<S: ToString>(message: S) -> Self {
         models::Error {
             message: Some(message.to_string()),
             ..Default::default()
         }
     }
+    #[allow(clippy::self_named_constructors)]
     fn error<S: ToString>(error: S) -> Self {
         models::Error {
             error: Some(error.to_string()),
@@ -1,16 +1,18 @@
 use std::{
-    env, fs, io,
-    path::{Path, PathBuf},
+    env, fs,
+    ops::Deref,
+    path::Path,
+    sync::Mutex,
     time::{Duration, Instant},
 };
 
-use actix_web::web;
 use git2::{Commit, Oid, Repository, Signature, Time};
 use lazy_static::lazy_static;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::{
     error::Error,
-    metrics::{REPO_CHEKOUT_TIME, REPO_COMMIT_TIME, REPO_FETCH_TIME, REPO_PUSH_TIME},
+    metrics::{REPO_COMMIT_TIME, REPO_FETCH_TIME, REPO_PUSH_TIME},
     models::metadata::Metadata,
 };
 
@@ -27,51 +29,154 @@ lazy_static! {
         tracing::error!(error = e.to_string().as_str(), "GIT_REPO_URL not found");
         std::process::exit(1)
     });
+    // Repos in pool share one clone, so fetches must not run concurrently. Only held on
+    // blocking threads, see ReusableRepoPool::fetch_updates().
+    static ref FETCH_LOCK: Mutex<()> = Mutex::new(());
+    static ref REPO_POOL_TIMEOUT: Duration = match env::var("REPO_POOL_TIMEOUT_SECONDS") {
+        Ok(seconds) => Duration::from_secs(seconds.parse().unwrap_or_else(
+            |e: std::num::ParseIntError| {
+                tracing::error!(
+                    error = e.to_string().as_str(),
+                    "invalid REPO_POOL_TIMEOUT_SECONDS"
+                );
+                std::process::exit(1)
+            }
+        )),
+        Err(_) => Duration::from_secs(60),
+    };
 }
 
+/// Pool of repos handed out in the order they are requested. Repos are returned to
+/// the pool when the guard from get() is dropped.
+///
+/// All repos in pool are handles to the same clone, so they share objects and refs,
+/// and a fetch by one of them updates all. Reads go straight to the object database,
+/// so handles don't need a working tree of their own.
 pub struct ReusableRepoPool {
-    repos: Vec<Repository>,
+    path: String,
+    repos: Mutex<Vec<Repository>>,
+    semaphore: Semaphore,
+    timeout: Duration,
 }
 
 impl ReusableRepoPool {
-    /// Create a pool of repos, each in a subfolder of the given path.
+    /// Create a pool of repos, all handles to a clone in a subfolder of the given path.
     pub fn new(git_repo_url: String, root_path: String, size: u64) -> Result<Self, Error> {
         let path = format!("{}/0", root_path);
         // Repo might already exist (persistent volume)
-        Repository::open(&path).or_else(|_| Repository::clone(&git_repo_url, &path))?;
-
-        // Create n copies of the same repo, no need to clone n-1 more times.
-        for i in 1..size {
-            let destination = format!("{}/{}", root_path, i);
-            if !Path::exists(destination.as_ref()) {
-                copy_dir_recursive(&path, destination)?;
-            }
-        }
+        let repo = Repository::open(&path).or_else(|_| Repository::clone(&git_repo_url, &path))?;
+        prune_commit_branches(&repo)?;
+        remove_repo_copies(&root_path, &git_repo_url)?;
 
         let repos = (0..size)
-            .map(|i| {
-                let path = format!("{}/{}", root_path, i);
-                Ok(Repository::open(&path)?)
-            })
-            .collect::<Result<_, git2::Error>>()?;
+            .map(|_| Repository::open(&path))
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+
+        Ok(Self {
+            path,
+            semaphore: Semaphore::new(repos.len()),
+            repos: Mutex::new(repos),
+            timeout: *REPO_POOL_TIMEOUT,
+        })
+    }
 
-        Ok(Self { repos })
+    /// Set how long get() waits for an available repo.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
     }
 
-    /// Get an available repository from pool. Must call push(repo) to put it back when done.
-    pub async fn pop(pool: &web::Data<async_lock::Mutex<ReusableRepoPool>>) -> Repository {
-        loop {
-            if let Some(repo) = pool.lock().await.repos.pop() {
-                return repo;
-            }
-            async_std::task::sleep(Duration::from_millis(10)).await;
+    /// Get an available repo from pool, waiting for one to be returned if all are in
+    /// use. The repo is put back in pool when the returned guard is dropped.
+    pub async fn get(&self) -> Result<PooledRepo<'_>, Error> {
+        let permit = tokio::time::timeout(self.timeout, self.semaphore.acquire())
+            .await
+            .map_err(|_| {
+                Error::ServiceUnavailable("timed out waiting for an available repo".to_string())
+            })?
+            .map_err(|e| Error::String(e.to_string()))?;
+
+        // A permit is only available when there is a repo in pool.
+        let repo = self
+            .repos
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop()
+            .ok_or_else(|| Error::String("no repo in pool".to_string()))?;
+
+        Ok(PooledRepo {
+            pool: self,
+            repo: Some(repo),
+            _permit: permit,
+        })
+    }
+
+    /// Checkout main and fetch updates on a blocking thread, with a handle of its own
+    /// so that neither async workers nor repos in pool wait for the fetch. Returns
+    /// true if main was updated.
+    pub async fn fetch_updates(&self) -> Result<bool, Error> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            checkout_main_and_fetch_updates(&Repository::open(path)?)
+        })
+        .await
+        .map_err(|e| Error::String(e.to_string()))?
+    }
+}
+
+/// Repo from pool, put back in pool when dropped.
+pub struct PooledRepo<'a> {
+    pool: &'a ReusableRepoPool,
+    repo: Option<Repository>,
+    // Released after the repo is put back in pool, since fields are dropped after drop().
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for PooledRepo<'_> {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        self.repo.as_ref().expect("repo is only taken when dropped")
+    }
+}
+
+impl Drop for PooledRepo<'_> {
+    fn drop(&mut self) {
+        if let Some(repo) = self.repo.take() {
+            self.pool
+                .repos
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(repo);
+        }
+    }
+}
+
+/// Delete branches named after commit oids, left behind by checking out old commits
+/// for reads. HEAD is moved back to main first, if it is on one of them.
+fn prune_commit_branches(repo: &Repository) -> Result<(), Error> {
+    let is_commit_branch =
+        |name: &str| name.len() == 40 && name.chars().all(|c| c.is_ascii_hexdigit());
+
+    let head_on_commit_branch = repo
+        .head()
+        .ok()
+        .and_then(|head| head.shorthand().map(is_commit_branch))
+        .unwrap_or(false);
+    if head_on_commit_branch {
+        if repo.find_reference("refs/heads/main").is_err() {
+            return Ok(());
         }
+        repo.set_head("refs/heads/main")?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
     }
 
-    /// Put a repo back in pool.
-    pub async fn push(pool: &web::Data<async_lock::Mutex<ReusableRepoPool>>, repo: Repository) {
-        pool.lock().await.repos.push(repo);
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+        let (mut branch, _) = branch?;
+        if branch.name()?.is_some_and(is_commit_branch) {
+            branch.delete()?;
+        }
     }
+    Ok(())
 }
 
 /// Metadata.
@@ -86,6 +191,7 @@ pub async fn repo_metadata(repo: &Repository) -> Result<Metadata, Error> {
 
 /// Checkout main branch and fetch updates.
 pub fn checkout_main_and_fetch_updates(repo: &Repository) -> Result<bool, Error> {
+    let _lock = FETCH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let start_time = Instant::now();
 
     let refname = "refs/heads/main";
@@ -117,7 +223,7 @@ pub fn checkout_main_and_fetch_updates(repo: &Repository) -> Result<bool, Error>
             repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
             Ok(true)
         } else {
-            Err(Error::from("Not able to fast-forward repo."))
+            Err(Error::Conflict("not able to fast-forward repo".to_string()))
         }
     } else {
         Ok(false)
@@ -134,7 +240,12 @@ pub fn checkout_main_and_fetch_updates(repo: &Repository) -> Result<bool, Error>
 pub fn list_commit_times(repo: &Repository) -> Result<Vec<(Time, Oid)>, Error> {
     let mut revwalk = repo.revwalk()?;
     revwalk.set_sorting(git2::Sort::TIME | git2::Sort::REVERSE)?;
-    revwalk.push_head()?;
+    // Walk from main rather than HEAD, since HEAD is moved when checking out a timestamp.
+    if repo.find_reference("refs/heads/main").is_ok() {
+        revwalk.push_ref("refs/heads/main")?;
+    } else {
+        revwalk.push_head()?;
+    }
 
     let mut commit_times = Vec::new();
 
@@ -147,53 +258,140 @@ pub fn list_commit_times(repo: &Repository) -> Result<Vec<(Time, Oid)>, Error> {
     Ok(commit_times)
 }
 
-/// Checkout a timestamp. Returns false if no files exists at that point in time.
-pub fn checkout_timestamp(repo: &Repository, timestamp: u64) -> Result<bool, Error> {
-    let start_time = Instant::now();
+/// List commits that changed a file, alongside whether the file exists after the commit.
+pub fn list_file_commit_times(
+    repo: &Repository,
+    filename: &str,
+) -> Result<Vec<(Time, Oid, bool)>, Error> {
+    let file_id = |commit: &Commit| -> Result<Option<Oid>, Error> {
+        Ok(commit.tree()?.get_name(filename).map(|entry| entry.id()))
+    };
 
+    let mut file_commit_times = Vec::new();
+
+    for (time, oid) in list_commit_times(repo)? {
+        let commit = repo.find_commit(oid)?;
+        let current = file_id(&commit)?;
+        let previous = match commit.parents().next() {
+            Some(parent) => file_id(&parent)?,
+            None => None,
+        };
+
+        if current != previous {
+            file_commit_times.push((time, oid, current.is_some()));
+        }
+    }
+
+    Ok(file_commit_times)
+}
+
+/// Find the commit that was head of main at a timestamp. Returns None if no commits existed at that point in time.
+pub fn commit_at_timestamp(repo: &Repository, timestamp: u64) -> Result<Option<Oid>, Error> {
     let commit_times = list_commit_times(repo)?;
 
     let ts = timestamp as i64;
-    let result = match commit_times.binary_search_by(|(time, _)| time.seconds().cmp(&ts)) {
-        Err(0) => Ok(false),
-        Ok(i) | Err(i) => {
-            let oid = commit_times[i - 1].1;
-            let commit = repo.find_commit(oid)?;
-
-            match repo.branch(&oid.to_string(), &commit, false) {
-                Ok(_) => {}
-                Err(e) => {
-                    if e.class() != git2::ErrorClass::Reference
-                        && e.code() != git2::ErrorCode::Exists
-                    {
-                        return Err(e.into());
-                    }
-                }
-            }
+    match commit_times.binary_search_by(|(time, _)| time.seconds().cmp(&ts)) {
+        Ok(0) | Err(0) => Ok(None),
+        Ok(i) | Err(i) => Ok(Some(commit_times[i - 1].1)),
+    }
+}
 
-            let refname = format!("refs/heads/{}", &oid.to_string());
-            repo.set_head(&refname)?;
-            repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
-            Ok(true)
-        }
+/// Read all files in the tree of a commit, without checking it out. No commit means
+/// there are no files.
+pub fn read_files_at_commit(
+    repo: &Repository,
+    commit: Option<Oid>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let tree = match commit {
+        Some(oid) => repo.find_commit(oid)?.tree()?,
+        None => return Ok(Vec::new()),
     };
 
-    let elapsed_millis = start_time.elapsed().as_millis();
-    if let Ok(change) = result {
-        REPO_CHEKOUT_TIME
-            .with_label_values(&[&format!("{}", change)])
-            .observe(elapsed_millis as f64 / 1000.0);
+    let mut files = Vec::new();
+    for entry in tree.iter() {
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            continue;
+        }
+        let filename = entry
+            .name()
+            .ok_or_else(|| Error::String("filename err".to_string()))?;
+        files.push((
+            filename.to_string(),
+            repo.find_blob(entry.id())?.content().to_vec(),
+        ));
     }
+    Ok(files)
+}
 
-    result
+/// Read a file from the tree of a commit, without checking it out. Returns None if the file does not exist in commit.
+pub fn read_file_at_commit(
+    repo: &Repository,
+    oid: Oid,
+    filename: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let tree = repo.find_commit(oid)?.tree()?;
+    let content = match tree.get_name(filename) {
+        Some(entry) => Some(repo.find_blob(entry.id())?.content().to_vec()),
+        None => None,
+    };
+    Ok(content)
+}
+
+/// Changed files alongside their new content, or None if they were deleted.
+pub type FileChanges = Vec<(String, Option<Vec<u8>>)>;
+
+/// Files that differ between the trees of two commits, alongside their content in
+/// the `to` commit, or None if they don't exist in it.
+pub fn changed_files(repo: &Repository, from: Oid, to: Oid) -> Result<FileChanges, Error> {
+    let from_tree = repo.find_commit(from)?.tree()?;
+    let to_tree = repo.find_commit(to)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&from_tree), Some(&to_tree), None)?;
+
+    let mut files = Vec::new();
+    for delta in diff.deltas() {
+        let file = match delta.status() {
+            git2::Delta::Deleted => delta.old_file(),
+            _ => delta.new_file(),
+        };
+        let filename = match file.path().and_then(|path| path.to_str()) {
+            Some(filename) => filename.to_string(),
+            None => continue,
+        };
+        let content = match delta.status() {
+            git2::Delta::Deleted => None,
+            _ => Some(repo.find_blob(file.id())?.content().to_vec()),
+        };
+        files.push((filename, content));
+    }
+    Ok(files)
 }
 
 /// Commit file.
-pub async fn commit_file(repo: &Repository, path: &PathBuf, message: String) -> Result<(), Error> {
+pub async fn commit_file(repo: &Repository, path: &Path, message: String) -> Result<(), Error> {
+    commit_files(repo, &[path], message).await
+}
+
+/// Commit changes to multiple files in a single commit. Paths are relative to the
+/// repo root, and files that no longer exist are committed as deleted.
+pub async fn commit_files(
+    repo: &Repository,
+    paths: &[&Path],
+    message: String,
+) -> Result<(), Error> {
     let start_time = Instant::now();
 
+    let workdir = repo
+        .path()
+        .parent()
+        .ok_or::<Error>("invalid repo path".into())?;
     let mut index = repo.index()?;
-    index.add_path(path)?;
+    for path in paths {
+        if workdir.join(path).exists() {
+            index.add_path(path)?;
+        } else {
+            index.remove_path(path)?;
+        }
+    }
     index.write()?;
 
     let tree_id = index.write_tree()?;
@@ -221,6 +419,27 @@ pub async fn commit_file(repo: &Repository, path: &PathBuf, message: String) ->
     Ok(())
 }
 
+/// Discard uncommitted changes to the index and working directory, including
+/// untracked files, without moving any references.
+pub fn reset_working_tree(repo: &Repository) -> Result<(), Error> {
+    let mut index = repo.index()?;
+    // repo.head() fails when empty repo is cloned and no commits exist. In that case, the index is emptied.
+    match repo.head().and_then(|head| head.peel_to_tree()) {
+        Ok(tree) => index.read_tree(&tree)?,
+        Err(_) => index.clear()?,
+    }
+    index.write()?;
+    repo.checkout_index(
+        Some(&mut index),
+        Some(
+            git2::build::CheckoutBuilder::default()
+                .force()
+                .remove_untracked(true),
+        ),
+    )?;
+    Ok(())
+}
+
 /// Push commits.
 pub fn push_updates(repo: &Repository) -> Result<(), Error> {
     let start_time = Instant::now();
@@ -234,20 +453,29 @@ pub fn push_updates(repo: &Repository) -> Result<(), Error> {
     Ok(())
 }
 
-/// Copy folder recursively.
-pub fn copy_dir_recursive(
-    source: impl AsRef<Path>,
-    destination: impl AsRef<Path>,
-) -> io::Result<()> {
-    fs::create_dir_all(&destination)?;
-    for entry in fs::read_dir(source)? {
+/// Remove copies of the repo made by earlier versions of the pool, in numbered
+/// subfolders next to the clone in subfolder 0. Only repos cloned from the same url
+/// are removed, anything else in those subfolders is left as is.
+fn remove_repo_copies(root_path: &str, git_repo_url: &str) -> Result<(), Error> {
+    let is_repo_copy = |path: &Path| {
+        Repository::open(path)
+            .and_then(|repo| {
+                let origin = repo.find_remote("origin")?;
+                Ok(origin.url() == Some(git_repo_url))
+            })
+            .unwrap_or(false)
+    };
+
+    for entry in fs::read_dir(root_path)? {
         let entry = entry?;
-        let file_type = entry.file_type()?;
-        let target = destination.as_ref().join(entry.file_name());
-        if file_type.is_dir() {
-            copy_dir_recursive(entry.path(), target)?;
-        } else {
-            fs::copy(entry.path(), target)?;
+        let is_numbered = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+            .is_some_and(|i| i > 0);
+        if is_numbered && entry.file_type()?.is_dir() && is_repo_copy(&entry.path()) {
+            tracing::info!(path = entry.path().to_str(), "removing repo copy");
+            fs::remove_dir_all(entry.path())?;
         }
     }
     Ok(())
@@ -1,8 +1,15 @@
-use std::{env, io::Cursor, path::Path, time::Instant};
+use std::{
+    collections::HashSet,
+    env,
+    io::{self, Cursor},
+    path::Path,
+    time::Instant,
+};
 
 use base64::{engine::general_purpose, Engine as _};
-use git2::Repository;
+use git2::{Oid, Repository};
 use lazy_static::lazy_static;
+use oxigraph::model::NamedNode;
 use tokio::{
     fs::{remove_file, File},
     io::{AsyncReadExt, AsyncWriteExt},
@@ -10,10 +17,13 @@ use tokio::{
 
 use crate::{
     error::Error,
-    git::{checkout_timestamp, commit_file},
+    git::{
+        changed_files, commit_at_timestamp, commit_file, commit_files, list_file_commit_times,
+        read_file_at_commit, read_files_at_commit, reset_working_tree, FileChanges,
+    },
     metrics::FILE_READ_TIME,
     models,
-    rdf::RdfPrettifier,
+    rdf::{canonicalize_graph, isomorphic_graphs, validate_graph, RdfFormat, RdfPrettifier},
 };
 
 lazy_static! {
@@ -29,6 +39,47 @@ lazy_static! {
         tracing::error!(error = e.to_string().as_str(), "GIT_REPO_URL not found");
         std::process::exit(1)
     });
+    static ref GRAPH_COMPARISON: GraphComparison =
+        match env::var("GRAPH_COMPARISON").as_deref().unwrap_or("exact") {
+            "exact" => GraphComparison::Exact,
+            "semantic" => GraphComparison::Semantic,
+            other => {
+                tracing::error!(comparison = other, "invalid GRAPH_COMPARISON");
+                std::process::exit(1)
+            }
+        };
+}
+
+/// How a stored graph is compared with an incoming one to decide whether it changed,
+/// selected by GRAPH_COMPARISON environment variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphComparison {
+    /// Graphs are equal if their prettified serializations are identical (default).
+    Exact,
+    /// Graphs are equal if they contain the same triples, up to blank node labels.
+    Semantic,
+}
+
+impl GraphComparison {
+    /// Whether the stored graph and the incoming graph are considered equal.
+    pub fn equal(&self, stored: &str, incoming: &str) -> bool {
+        match self {
+            GraphComparison::Exact => stored == incoming,
+            // A stored graph that can't be parsed is treated as changed.
+            GraphComparison::Semantic => {
+                stored == incoming || isomorphic_graphs(stored, incoming).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Name of the file a graph is stored in, derived from graph id.
+pub fn graph_filename(id: &str) -> String {
+    let valid_graph_filename = general_purpose::STANDARD
+        .encode(id)
+        .replace('/', "_")
+        .replace('+', "-");
+    format!("{}.ttl", valid_graph_filename)
 }
 
 /// Store graph.
@@ -37,12 +88,121 @@ pub async fn store_graph<P: RdfPrettifier>(
     rdf_prettifier: &P,
     graph: &models::Graph,
 ) -> Result<(), Error> {
-    let graph_content = rdf_prettifier.prettify(&graph.graph).await?;
+    let result = async {
+        if let Some(filename) = write_graph(repo, rdf_prettifier, graph).await? {
+            commit_file(repo, Path::new(&filename), format!("update: {}", graph.id)).await?;
+        }
+        Ok::<(), Error>(())
+    }
+    .await;
+    discard_changes_on_error(repo, result.is_err());
+
+    // Push ever x seconds instead
+    // push_updates(&repo)?;
+
+    result
+}
+
+/// Store multiple graphs in a single commit. A graph that fails does not stop the
+/// others from being stored, and the status of each graph is returned in order.
+pub async fn store_graphs<P: RdfPrettifier>(
+    repo: &Repository,
+    rdf_prettifier: &P,
+    graphs: &[models::Graph],
+) -> Result<Vec<models::GraphStatus>, Error> {
+    let mut filenames = Vec::new();
+    let mut statuses = Vec::with_capacity(graphs.len());
+
+    for graph in graphs {
+        let result = write_graph(repo, rdf_prettifier, graph).await;
+        statuses.push(graph_status(graph, result, &mut filenames));
+    }
+
+    filenames.sort();
+    filenames.dedup();
+    let result = if filenames.is_empty() {
+        Ok(())
+    } else {
+        let paths = filenames.iter().map(Path::new).collect::<Vec<&Path>>();
+        commit_files(repo, &paths, format!("update: {} graphs", filenames.len())).await
+    };
+    discard_changes_on_error(repo, result.is_err() || any_failed(&statuses));
+
+    result.map(|_| statuses)
+}
 
-    let valid_graph_filename = general_purpose::STANDARD.encode(&graph.id)
-        .replace("/", "_")
-        .replace("+", "-");
-    let filename = format!("{}.ttl", valid_graph_filename);
+fn any_failed(statuses: &[models::GraphStatus]) -> bool {
+    statuses
+        .iter()
+        .any(|status| status.status == models::graph_status::Status::Failed)
+}
+
+/// Reset the working tree to HEAD if something went wrong, so that files written by
+/// a failed or partially applied write don't leak into later commits.
+fn discard_changes_on_error(repo: &Repository, failed: bool) {
+    if !failed {
+        return;
+    }
+    if let Err(e) = reset_working_tree(repo) {
+        tracing::error!(error = e.to_string(), "unable to reset working tree");
+    }
+}
+
+/// Status of a graph given the result of writing it, collecting the filename of
+/// changed graphs.
+fn graph_status(
+    graph: &models::Graph,
+    result: Result<Option<String>, Error>,
+    filenames: &mut Vec<String>,
+) -> models::GraphStatus {
+    match result {
+        Ok(Some(filename)) => {
+            filenames.push(filename);
+            models::GraphStatus::new(graph.id.clone(), models::graph_status::Status::Changed)
+        }
+        Ok(None) => {
+            models::GraphStatus::new(graph.id.clone(), models::graph_status::Status::Unchanged)
+        }
+        Err(e) => {
+            tracing::warn!(
+                id = graph.id.as_str(),
+                error = e.to_string(),
+                "unable to store graph"
+            );
+            let mut status =
+                models::GraphStatus::new(graph.id.clone(), models::graph_status::Status::Failed);
+            status.error = Some(e.to_string());
+            status
+        }
+    }
+}
+
+/// Graphs that can't be canonicalized are rejected when stored, while failing to
+/// canonicalize a stored graph when reading it is a server error.
+fn uncanonicalizable_graph(e: Error) -> Error {
+    match e {
+        Error::Canonicalization(_) => Error::BadRequest(e.to_string()),
+        e => e,
+    }
+}
+
+/// Prettify graph and write it to its file in the working directory, without
+/// committing. Returns the filename if the graph changed, None otherwise.
+async fn write_graph<P: RdfPrettifier>(
+    repo: &Repository,
+    rdf_prettifier: &P,
+    graph: &models::Graph,
+) -> Result<Option<String>, Error> {
+    let format = RdfFormat::from_media_type(graph.format.as_deref())?;
+    validate_graph(&graph.graph, format)?;
+    let graph_content = rdf_prettifier
+        .prettify(&graph.graph, format)
+        .await
+        .map_err(uncanonicalizable_graph)?;
+    let graph_content =
+        canonicalize_graph(graph_content, &graph.id).map_err(uncanonicalizable_graph)?;
+
+    let filename = graph_filename(&graph.id);
     let path = repo
         .path()
         .parent()
@@ -52,12 +212,12 @@ pub async fn store_graph<P: RdfPrettifier>(
     let abort_due_to_no_change = if let Ok(mut file) = File::open(&path).await {
         let mut contents = vec![];
         file.read_to_end(&mut contents).await?;
-        String::from_utf8(contents)? == graph_content
+        GRAPH_COMPARISON.equal(&String::from_utf8(contents)?, &graph_content)
     } else {
         false
     };
     if abort_due_to_no_change {
-        return Ok(());
+        return Ok(None);
     }
 
     let mut file = File::create(&path).await?;
@@ -65,71 +225,212 @@ pub async fn store_graph<P: RdfPrettifier>(
     file.write_all_buf(&mut buffer).await?;
     file.shutdown().await?;
 
-    commit_file(
-        &repo,
-        &Path::new(&filename).into(),
-        format!("update: {}", graph.id),
-    )
-    .await?;
+    Ok(Some(filename))
+}
 
+/// Delete graph.
+pub async fn delete_graph(repo: &Repository, id: String) -> Result<(), Error> {
+    let filename = remove_graph_file(repo, &id).await?;
+    let result = commit_file(repo, Path::new(&filename), format!("delete: {}", id)).await;
+    discard_changes_on_error(repo, result.is_err());
     // Push ever x seconds instead
     // push_updates(&repo)?;
 
-    Ok(())
+    result
 }
 
-/// Delete graph.
-pub async fn delete_graph(repo: &Repository, id: String) -> Result<(), Error> {
-    let valid_graph_filename = general_purpose::STANDARD.encode(&id).replace("/", "_").replace("+", "-");
-    let filename = format!("{}.ttl", valid_graph_filename);
-    let path = repo.path().join(Path::new(&filename));
+/// Replace the complete set of graphs whose id starts with namespace in a single
+/// commit. Uploaded graphs are stored, and graphs in the namespace that are missing
+/// from the upload are deleted. Graphs that fail to be stored are left as they are.
+pub async fn replace_graphs<P: RdfPrettifier>(
+    repo: &Repository,
+    rdf_prettifier: &P,
+    namespace: &str,
+    graphs: &[models::Graph],
+) -> Result<Vec<models::GraphStatus>, Error> {
+    // An empty namespace would delete every graph not in the upload.
+    if namespace.is_empty() {
+        return Err(Error::BadRequest("namespace must not be empty".to_string()));
+    }
 
-    remove_file(&path).await?;
-    commit_file(&repo, &path, format!("delete: {}", id)).await?;
-    // Push ever x seconds instead
-    // push_updates(&repo)?;
+    let mut filenames = Vec::new();
+    let mut statuses = Vec::with_capacity(graphs.len());
+
+    for graph in graphs {
+        let result = if graph.id.starts_with(namespace) {
+            write_graph(repo, rdf_prettifier, graph).await
+        } else {
+            Err(Error::BadRequest(format!(
+                "graph id is not in namespace '{}'",
+                namespace
+            )))
+        };
+        statuses.push(graph_status(graph, result, &mut filenames));
+    }
+
+    let uploaded = graphs
+        .iter()
+        .map(|graph| graph.id.as_str())
+        .collect::<HashSet<&str>>();
+    let result = async {
+        for id in list_graph_ids(repo)? {
+            if id.starts_with(namespace) && !uploaded.contains(id.as_str()) {
+                filenames.push(remove_graph_file(repo, &id).await?);
+                statuses.push(models::GraphStatus::new(
+                    id,
+                    models::graph_status::Status::Deleted,
+                ));
+            }
+        }
+
+        filenames.sort();
+        filenames.dedup();
+        if !filenames.is_empty() {
+            let paths = filenames.iter().map(Path::new).collect::<Vec<&Path>>();
+            commit_files(repo, &paths, format!("replace: {}", namespace)).await?;
+        }
+        Ok::<(), Error>(())
+    }
+    .await;
+    // Graphs written or removed before an error must not be left in the working tree.
+    discard_changes_on_error(repo, result.is_err() || any_failed(&statuses));
+
+    result.map(|_| statuses)
+}
+
+/// Remove the file of a graph from the working directory, without committing.
+/// Returns the filename.
+async fn remove_graph_file(repo: &Repository, id: &str) -> Result<String, Error> {
+    let filename = graph_filename(id);
+    let path = repo
+        .path()
+        .parent()
+        .ok_or::<Error>("invalid repo path".into())?
+        .join(Path::new(&filename));
+
+    remove_file(&path).await.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::NotFound(format!("graph '{}' does not exist", id)),
+        _ => e.into(),
+    })?;
+    Ok(filename)
+}
+
+/// Ids of all graphs in the working directory.
+fn list_graph_ids(repo: &Repository) -> Result<Vec<String>, Error> {
+    let repo_dir = repo
+        .path()
+        .parent()
+        .ok_or::<Error>("invalid repo path".into())?;
+
+    let mut ids = Vec::new();
+    for entry in repo_dir.read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(id) = entry.file_name().to_str().and_then(graph_id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
 
-    Ok(())
+const GRAPH_NAME_PREFIX: &str = "urn:rdf-diff-store:graph:";
+
+/// Name of the named graph a graph is loaded into. Graph ids that are not IRIs, or
+/// that could be mistaken for an encoded id, are percent-encoded into a URN.
+pub fn graph_name(id: &str) -> NamedNode {
+    if !id.starts_with(GRAPH_NAME_PREFIX) {
+        if let Ok(name) = NamedNode::new(id) {
+            return name;
+        }
+    }
+
+    let encoded = id
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect::<String>();
+    NamedNode::new_unchecked(format!("{}{}", GRAPH_NAME_PREFIX, encoded))
+}
+
+/// Graph id a file is stored for, derived from filename. The inverse of graph_filename.
+pub fn graph_id(filename: &str) -> Option<String> {
+    let encoded = filename
+        .strip_suffix(".ttl")?
+        .replace('_', "/")
+        .replace('-', "+");
+    let id = general_purpose::STANDARD.decode(encoded).ok()?;
+    String::from_utf8(id).ok()
 }
 
-/// Fetch all graphs for a given timestamp.
+/// Fetch all graphs for a given timestamp, alongside their ids.
 pub async fn read_all_graph_files(
     repo: &Repository,
     timestamp: u64,
-) -> Result<Vec<Vec<u8>>, Error> {
-    let result = if checkout_timestamp(&repo, timestamp)? {
-        let repo_dir = repo
-            .path()
-            .parent()
-            .ok_or::<Error>("invalid repo path".into())?;
-        read_all_files(repo_dir).await?
-    } else {
-        Vec::new()
-    };
-
-    Ok(result)
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    read_all_graph_files_at_commit(repo, commit_at_timestamp(repo, timestamp)?).await
 }
 
-/// Read all files in repository's root dir. Not recursively.
-async fn read_all_files(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
+/// Fetch all graphs as of a commit, alongside their ids, read from the commit tree
+/// without checking it out. There are no graphs if there is no commit.
+pub async fn read_all_graph_files_at_commit(
+    repo: &Repository,
+    commit: Option<Oid>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
     let start_time = Instant::now();
 
-    let mut files = Vec::new();
-    for filepath in path.read_dir()? {
-        let filepath = filepath?;
-        if filepath.file_type()?.is_file()
-            && filepath
-                .file_name()
-                .into_string()
-                .map_err(|_| Error::String("filename err".to_string()))?
-                .ends_with(".ttl")
-        {
-            files.push(tokio::fs::read(filepath.path()).await?)
-        }
-    }
+    // Files whose names are not encoded graph ids are not graphs.
+    let files = read_files_at_commit(repo, commit)?
+        .into_iter()
+        .filter_map(|(filename, content)| graph_id(&filename).map(|id| (id, content)))
+        .collect();
 
     let elapsed_millis = start_time.elapsed().as_millis();
     FILE_READ_TIME.observe(elapsed_millis as f64 / 1000.0);
 
     Ok(files)
 }
+
+/// Graphs that changed between two commits, alongside their content in the `to`
+/// commit, or None if they were deleted.
+pub fn changed_graphs(repo: &Repository, from: Oid, to: Oid) -> Result<FileChanges, Error> {
+    Ok(changed_files(repo, from, to)?
+        .into_iter()
+        .filter_map(|(filename, content)| graph_id(&filename).map(|id| (id, content)))
+        .collect())
+}
+
+/// Fetch a single graph as it was at a given timestamp, read directly from the
+/// commit tree. Returns None if the graph did not exist at that point in time.
+pub fn read_graph_file(
+    repo: &Repository,
+    id: &str,
+    timestamp: u64,
+) -> Result<Option<Vec<u8>>, Error> {
+    match commit_at_timestamp(repo, timestamp)? {
+        Some(oid) => read_file_at_commit(repo, oid, &graph_filename(id)),
+        None => Ok(None),
+    }
+}
+
+/// List every change made to a single graph, oldest first.
+pub fn graph_history(repo: &Repository, id: &str) -> Result<Vec<models::GraphChange>, Error> {
+    let history = list_file_commit_times(repo, &graph_filename(id))?
+        .into_iter()
+        .map(|(time, oid, exists)| {
+            let operation = if exists {
+                models::graph_change::Operation::Update
+            } else {
+                models::graph_change::Operation::Delete
+            };
+            models::GraphChange::new(oid.to_string(), time.seconds(), operation)
+        })
+        .collect();
+
+    Ok(history)
+}
@@ -2,11 +2,13 @@
 extern crate serde;
 
 pub mod api;
+pub mod canonical;
 pub mod error;
 pub mod git;
 pub mod graphs;
 pub mod metrics;
 #[allow(dead_code, non_snake_case)]
 pub mod models;
+pub mod persistence;
 pub mod query;
 pub mod rdf;
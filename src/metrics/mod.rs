@@ -7,9 +7,11 @@ pub mod middleware;
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
+    /// Counted by items cached, their estimated size in bytes, and items evicted by
+    /// cause: evicted_size, evicted_expired or evicted_too_large.
     pub static ref CACHE_COUNT: IntGaugeVec = IntGaugeVec::new(
         Opts::new("cache_count", "Number of items cached"),
-        &["type"]
+        &["type", "count"]
     )
     .unwrap_or_else(|e| {
         tracing::error!(error = e.to_string(), "cache_count metric error");
@@ -69,17 +71,6 @@ lazy_static! {
         tracing::error!(error = e.to_string(), "repo_fetch_time");
         std::process::exit(1);
     });
-    pub static ref REPO_CHEKOUT_TIME: HistogramVec = HistogramVec::new(
-        HistogramOpts {
-            common_opts: Opts::new("repo_checkout_time", "Repo Checkout Times"),
-            buckets: vec![0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 5.0],
-        },
-        &["change"]
-    )
-    .unwrap_or_else(|e| {
-        tracing::error!(error = e.to_string(), "repo_checkout_time");
-        std::process::exit(1);
-    });
     pub static ref FILE_READ_TIME: Histogram = Histogram::with_opts(HistogramOpts {
         common_opts: Opts::new("file_read_time", "File Read Time"),
         buckets: vec![0.1, 0.5, 1.0, 2.5, 5.0, 7.5, 10.0],
@@ -130,13 +121,6 @@ pub fn register_metrics() {
             std::process::exit(1);
         });
 
-    REGISTRY
-        .register(Box::new(REPO_CHEKOUT_TIME.clone()))
-        .unwrap_or_else(|e| {
-            tracing::error!(error = e.to_string(), "repo_checkout_time collector error");
-            std::process::exit(1);
-        });
-
     REGISTRY
         .register(Box::new(REPO_COMMIT_TIME.clone()))
         .unwrap_or_else(|e| {
@@ -0,0 +1,33 @@
+/*
+ * RDF Query Cache
+ *
+ * API for querying diff-store with cache
+ *
+ * The version of the OpenAPI document: 0.1.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Diff {
+    /// Triples added between the two timestamps, as N-Triples.
+    #[serde(rename = "added")]
+    pub added: String,
+    /// Triples removed between the two timestamps, as N-Triples.
+    #[serde(rename = "removed")]
+    pub removed: String,
+}
+
+impl Diff {
+    pub fn new(added: String, removed: String) -> Diff {
+        Diff {
+            added,
+            removed,
+        }
+    }
+}
+
+
@@ -13,17 +13,44 @@
 
 #[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
 pub struct Error {
+    /// URI identifying the problem type.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    /// Short human-readable summary of the problem type.
+    #[serde(rename = "title", skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// HTTP status code.
+    #[serde(rename = "status", skip_serializing_if = "Option::is_none")]
+    pub status: Option<i32>,
+    /// Human-readable explanation specific to this occurrence of the problem.
+    #[serde(rename = "detail", skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// URI reference identifying this occurrence of the problem.
+    #[serde(rename = "instance", skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Machine-readable error code.
+    #[serde(rename = "code", skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
     #[serde(rename = "message", skip_serializing_if = "Option::is_none")]
     pub message: Option<String>,
     #[serde(rename = "error", skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    #[serde(rename = "position", skip_serializing_if = "Option::is_none")]
+    pub position: Option<Box<crate::models::ErrorPosition>>,
 }
 
 impl Error {
     pub fn new() -> Error {
         Error {
+            r#type: None,
+            title: None,
+            status: None,
+            detail: None,
+            instance: None,
+            code: None,
             message: None,
             error: None,
+            position: None,
         }
     }
 }
@@ -0,0 +1,39 @@
+/*
+ * RDF Query Cache
+ *
+ * API for querying diff-store with cache
+ *
+ * The version of the OpenAPI document: 0.1.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+/// ErrorPosition : Position of a syntax error in a query.
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct ErrorPosition {
+    /// Line, starting at 1.
+    #[serde(rename = "line")]
+    pub line: i64,
+    /// Column, starting at 1.
+    #[serde(rename = "column")]
+    pub column: i64,
+    /// Byte offset, starting at 0.
+    #[serde(rename = "offset")]
+    pub offset: i64,
+}
+
+impl ErrorPosition {
+    /// Position of a syntax error in a query.
+    pub fn new(line: i64, column: i64, offset: i64) -> ErrorPosition {
+        ErrorPosition {
+            line,
+            column,
+            offset,
+        }
+    }
+}
+
+
@@ -0,0 +1,45 @@
+/*
+ * RDF Query Cache
+ *
+ * API for querying diff-store with cache
+ *
+ * The version of the OpenAPI document: 0.1.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct GraphChange {
+    /// Oid of the commit that changed the graph.
+    #[serde(rename = "commit")]
+    pub commit: String,
+    #[serde(rename = "timestamp")]
+    pub timestamp: i64,
+    /// Whether the commit updated or deleted the graph.
+    #[serde(rename = "operation")]
+    pub operation: Operation,
+}
+
+impl GraphChange {
+    pub fn new(commit: String, timestamp: i64, operation: Operation) -> GraphChange {
+        GraphChange {
+            commit,
+            timestamp,
+            operation,
+        }
+    }
+}
+
+/// Whether the commit updated or deleted the graph.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize)]
+pub enum Operation {
+    #[default]
+    #[serde(rename = "update")]
+    Update,
+    #[serde(rename = "delete")]
+    Delete,
+}
+
@@ -0,0 +1,49 @@
+/*
+ * RDF Query Cache
+ *
+ * API for querying diff-store with cache
+ *
+ * The version of the OpenAPI document: 0.1.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct GraphStatus {
+    #[serde(rename = "id")]
+    pub id: String,
+    /// Whether the graph was changed, unchanged, deleted or failed to be stored.
+    #[serde(rename = "status")]
+    pub status: Status,
+    /// Reason the graph failed to be stored.
+    #[serde(rename = "error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl GraphStatus {
+    pub fn new(id: String, status: Status) -> GraphStatus {
+        GraphStatus {
+            id,
+            status,
+            error: None,
+        }
+    }
+}
+
+/// Whether the graph was changed, unchanged, deleted or failed to be stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize)]
+pub enum Status {
+    #[default]
+    #[serde(rename = "changed")]
+    Changed,
+    #[serde(rename = "unchanged")]
+    Unchanged,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "deleted")]
+    Deleted,
+}
+
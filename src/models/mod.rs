@@ -1,6 +1,16 @@
+pub mod diff;
+pub use self::diff::Diff;
 pub mod error;
 pub use self::error::Error;
+pub mod error_position;
+pub use self::error_position::ErrorPosition;
 pub mod graph;
 pub use self::graph::Graph;
+pub mod graph_change;
+pub use self::graph_change::GraphChange;
+pub mod graph_status;
+pub use self::graph_status::GraphStatus;
 pub mod metadata;
 pub use self::metadata::Metadata;
+pub mod refresh;
+pub use self::refresh::Refresh;
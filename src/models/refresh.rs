@@ -0,0 +1,33 @@
+/*
+ * RDF Query Cache
+ *
+ * API for querying diff-store with cache
+ *
+ * The version of the OpenAPI document: 0.1.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Refresh {
+    /// Whether main was updated by the fetch.
+    #[serde(rename = "updated")]
+    pub updated: bool,
+    /// Commit at head of main after the fetch, if there are any commits.
+    #[serde(rename = "head", skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+}
+
+impl Refresh {
+    pub fn new(updated: bool) -> Refresh {
+        Refresh {
+            updated,
+            head: None,
+        }
+    }
+}
+
+
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+};
+
+use git2::Oid;
+use lazy_static::lazy_static;
+use oxigraph::store::Store;
+
+use crate::error::Error;
+
+lazy_static! {
+    static ref STORE_CACHE_PATH: Option<String> = env::var("STORE_CACHE_PATH")
+        .ok()
+        .filter(|path| !path.is_empty());
+    static ref STORE_CACHE_MAX_BYTES: u64 = match env::var("STORE_CACHE_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes
+            .parse()
+            .unwrap_or_else(|e: std::num::ParseIntError| {
+                tracing::error!(
+                    error = e.to_string().as_str(),
+                    "invalid STORE_CACHE_MAX_BYTES"
+                );
+                std::process::exit(1)
+            }),
+        Err(_) => 10 * 1024 * 1024 * 1024,
+    };
+}
+
+/// Suffix of directories with stores that are still being written.
+const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+/// On-disk graph stores, one directory per commit, so that cached stores survive
+/// restarts. Stores are opened when first used, and evicted by modification time
+/// when their total size exceeds the byte budget.
+pub struct PersistentStores {
+    path: PathBuf,
+    max_bytes: u64,
+    lock: Mutex<()>,
+    open_stores: Arc<Mutex<OpenStores>>,
+}
+
+/// Stores that are open, by commit. A store is only opened once at a time, as
+/// RocksDB locks its directory.
+type OpenStores = HashMap<Oid, OpenStore>;
+
+struct OpenStore {
+    store: Weak<PersistedStore>,
+    /// Remove the store from disk when it is closed.
+    evicted: bool,
+}
+
+/// Persisted store, open until the last handle to it is dropped. An evicted store
+/// is removed from disk once it is closed.
+pub struct PersistedStore {
+    commit: Oid,
+    path: PathBuf,
+    store: Option<Store>,
+    open_stores: Arc<Mutex<OpenStores>>,
+}
+
+impl Deref for PersistedStore {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        self.store.as_ref().expect("store is open until dropped")
+    }
+}
+
+impl Drop for PersistedStore {
+    fn drop(&mut self) {
+        let mut open_stores = lock(&self.open_stores);
+        // Close the store before its directory can be removed or opened again.
+        drop(self.store.take());
+        let evicted = open_stores
+            .remove(&self.commit)
+            .is_some_and(|open_store| open_store.evicted);
+        if evicted {
+            if let Err(e) = fs::remove_dir_all(&self.path) {
+                tracing::warn!(
+                    error = e.to_string().as_str(),
+                    commit = self.commit.to_string().as_str(),
+                    "unable to remove evicted store"
+                );
+            }
+        }
+    }
+}
+
+impl PersistentStores {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes,
+            lock: Mutex::new(()),
+            open_stores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Persistent stores configured by STORE_CACHE_PATH and STORE_CACHE_MAX_BYTES
+    /// environment variables, if STORE_CACHE_PATH is set.
+    pub fn from_env() -> Option<Self> {
+        STORE_CACHE_PATH
+            .as_ref()
+            .map(|path| Self::new(path, *STORE_CACHE_MAX_BYTES))
+    }
+
+    /// Lock persistent stores, so that a commit is only persisted once at a time.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        lock(&self.lock)
+    }
+
+    /// Remove stores that were not completely written, e.g. because of a restart.
+    pub fn remove_incomplete(&self) -> Result<(), Error> {
+        fs::create_dir_all(&self.path)?;
+
+        for (dir, name) in self.store_dirs()? {
+            if name.ends_with(INCOMPLETE_SUFFIX) {
+                fs::remove_dir_all(&dir)?;
+            } else if Oid::from_str(&name).is_err() {
+                tracing::warn!(dir = name, "ignoring unknown directory in store cache");
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the persisted store of a commit, or share it if it's already open. None
+    /// if the commit is not persisted.
+    pub fn open(&self, commit: Oid) -> Result<Option<Arc<PersistedStore>>, Error> {
+        loop {
+            let mut open_stores = lock(&self.open_stores);
+            if let Some(open_store) = open_stores.get_mut(&commit) {
+                match open_store.store.upgrade() {
+                    Some(store) => {
+                        open_store.evicted = false;
+                        return Ok(Some(store));
+                    }
+                    // The last handle is being dropped, wait for the store to be closed.
+                    None => {
+                        drop(open_stores);
+                        thread::yield_now();
+                        continue;
+                    }
+                }
+            }
+
+            let path = self.store_path(commit);
+            if !path.is_dir() {
+                return Ok(None);
+            }
+            let store = Arc::new(PersistedStore {
+                commit,
+                store: Some(Store::open(&path)?),
+                path,
+                open_stores: self.open_stores.clone(),
+            });
+            open_stores.insert(
+                commit,
+                OpenStore {
+                    store: Arc::downgrade(&store),
+                    evicted: false,
+                },
+            );
+            return Ok(Some(store));
+        }
+    }
+
+    /// Write a copy of the store of a commit to disk, and return the on-disk store. If
+    /// the commit is already persisted, the existing store is opened instead.
+    pub fn persist(&self, commit: Oid, store: &Store) -> Result<Arc<PersistedStore>, Error> {
+        if let Some(persisted) = self.open(commit)? {
+            return Ok(persisted);
+        }
+        fs::create_dir_all(&self.path)?;
+
+        let incomplete_path = self.path.join(format!("{}{}", commit, INCOMPLETE_SUFFIX));
+        if incomplete_path.exists() {
+            fs::remove_dir_all(&incomplete_path)?;
+        }
+
+        // Stores are written to a separate directory and moved into place when
+        // complete, so that a restart never opens a partially written store.
+        let persisted = Store::open(&incomplete_path)?;
+        persisted
+            .bulk_loader()
+            .load_ok_quads::<_, Error>(store.iter())?;
+        persisted.flush()?;
+        drop(persisted);
+
+        fs::rename(&incomplete_path, self.store_path(commit))?;
+
+        self.open(commit)?
+            .ok_or_else(|| Error::String(format!("persisted store of {} is missing", commit)))
+    }
+
+    /// Remove persisted stores, least recently written first, until their total size
+    /// is within the byte budget. The store of `keep` is never removed, and stores
+    /// that are open are removed once closed. Returns the commits of the evicted stores.
+    pub fn evict(&self, keep: Oid) -> Result<Vec<Oid>, Error> {
+        let mut stores = Vec::new();
+        for (dir, name) in self.store_dirs()? {
+            if let Ok(commit) = Oid::from_str(&name) {
+                let modified = fs::metadata(&dir)?.modified()?;
+                stores.push((modified, commit, dir_size(&dir)?));
+            }
+        }
+        stores.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut total_bytes = stores.iter().map(|(_, _, size)| size).sum::<u64>();
+        let mut evicted = Vec::new();
+        let mut open_stores = lock(&self.open_stores);
+        for (_, commit, size) in stores {
+            if total_bytes <= self.max_bytes {
+                break;
+            }
+            if commit == keep {
+                continue;
+            }
+            match open_stores.get_mut(&commit) {
+                Some(open_store) => open_store.evicted = true,
+                None => fs::remove_dir_all(self.store_path(commit))?,
+            }
+            total_bytes -= size;
+            evicted.push(commit);
+        }
+        Ok(evicted)
+    }
+
+    fn store_path(&self, commit: Oid) -> PathBuf {
+        self.path.join(commit.to_string())
+    }
+
+    /// Directories in the store cache directory, alongside their names.
+    fn store_dirs(&self) -> Result<Vec<(PathBuf, String)>, Error> {
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    dirs.push((entry.path(), name.to_string()));
+                }
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Total size of all files in a directory.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
@@ -1,19 +1,90 @@
-use std::{fmt, string::FromUtf8Error, time::Instant};
+use std::{
+    env, fmt,
+    hash::Hash,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use git2::Repository;
-use moka::sync::Cache;
-use oxigraph::{io::GraphFormat, model::GraphNameRef, sparql::QueryResultsFormat};
+use git2::{Oid, Repository};
+use lazy_static::lazy_static;
+use moka::{notification::RemovalCause, sync::Cache};
+use oxigraph::{
+    io::{GraphFormat, GraphParser},
+    model::GraphNameRef,
+    sparql::{EvaluationError, Query, QueryResults, QueryResultsFormat},
+};
 
 use crate::{
     error::Error,
-    graphs::read_all_graph_files,
-    metrics::{GRAPH_PARSE_TIME, QUERY_PROCESSING_TIME},
-    rdf::{to_turtle, RdfPrettifier},
+    git::{commit_at_timestamp, list_commit_times},
+    graphs::{changed_graphs, graph_name, read_all_graph_files_at_commit, read_graph_file},
+    metrics::{CACHE_COUNT, GRAPH_PARSE_TIME, QUERY_PROCESSING_TIME},
+    models,
+    persistence::{PersistedStore, PersistentStores},
+    rdf::{diff_stores, serialize_store, to_turtle, RdfFormat, RdfPrettifier, SnapshotFormat},
 };
 
+lazy_static! {
+    static ref CACHE_BUDGETS: CacheBudgets = CacheBudgets {
+        stores: env_u64("QUERY_CACHE_STORES_BYTES", 2 * 1024 * 1024 * 1024),
+        graphs: env_u64("QUERY_CACHE_GRAPHS_BYTES", 512 * 1024 * 1024),
+        formats: env_u64("QUERY_CACHE_FORMATS_BYTES", 512 * 1024 * 1024),
+        queries: env_u64("QUERY_CACHE_QUERIES_BYTES", 256 * 1024 * 1024),
+        time_to_idle: Duration::from_secs(env_u64("QUERY_CACHE_TIME_TO_IDLE_SECONDS", 60 * 60)),
+    };
+    /// Comma separated timestamps to preload stores for, in addition to the latest.
+    pub static ref WARM_UP_TIMESTAMPS: Vec<u64> = env::var("WARM_UP_TIMESTAMPS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|timestamp| !timestamp.is_empty())
+        .map(|timestamp| {
+            timestamp.parse().unwrap_or_else(|e: std::num::ParseIntError| {
+                tracing::error!(error = e.to_string().as_str(), "invalid WARM_UP_TIMESTAMPS");
+                std::process::exit(1)
+            })
+        })
+        .collect();
+    /// JSON array of SPARQL queries to preload results of for the latest commit.
+    pub static ref WARM_UP_QUERIES: Vec<String> = match env::var("WARM_UP_QUERIES") {
+        Ok(queries) => serde_json::from_str(&queries).unwrap_or_else(|e| {
+            tracing::error!(error = e.to_string().as_str(), "invalid WARM_UP_QUERIES");
+            std::process::exit(1)
+        }),
+        Err(_) => Vec::new(),
+    };
+}
+
+/// Estimated memory used by each quad of a store. Quads of named graphs are kept in
+/// six indexes, each with a key of four terms encoded in 17 bytes for IRIs, blank
+/// nodes and short literals, which comes to about 400 bytes. Longer literals are
+/// stored once more on top of that.
+const ESTIMATED_QUAD_BYTES: u64 = 400;
+
+/// Caches are weighed in KiB, as weights are u32 and a store of more than about 10
+/// million quads weighs more than u32::MAX bytes.
+const WEIGHT_UNIT_BYTES: u64 = 1024;
+
+/// Estimated memory used by an open persisted store, whose quads are on disk.
+const ESTIMATED_PERSISTED_STORE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Parse environment variable as u64, or use default if not set.
+fn env_u64(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|e: std::num::ParseIntError| {
+            tracing::error!(error = e.to_string().as_str(), name, "invalid cache budget");
+            std::process::exit(1)
+        }),
+        Err(_) => default,
+    }
+}
+
 #[derive(Debug)]
 pub enum CacheLevel {
     Nothing,
+    /// Store built from the cached store of a nearby commit.
+    Incremental,
     Graph,
     Query,
     Prettified,
@@ -25,81 +96,534 @@ impl fmt::Display for CacheLevel {
     }
 }
 
+/// Caches keyed by the commit that was head of main at the requested timestamp, so
+/// that all timestamps between two commits share entries. No commit means there
+/// were no graphs at that time.
+///
+/// With persistent stores, cached stores are kept on disk rather than in memory and
+/// reopened when first used after a restart.
 #[derive(Clone)]
 pub struct QueryCache {
-    pub store_cache: Cache<u64, oxigraph::store::Store>,
-    pub graphs_cache: Cache<u64, String>,
-    pub query_cache: Cache<(u64, String), String>,
+    pub store_cache: Cache<Option<Oid>, CachedStore>,
+    pub graphs_cache: Cache<Option<Oid>, String>,
+    pub formats_cache: Cache<(Option<Oid>, SnapshotFormat), String>,
+    pub query_cache: Cache<(Option<Oid>, String, ResultFormats), QueryResult>,
+    persistent_stores: Option<Arc<PersistentStores>>,
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Byte budgets of each cache, and how long entries are kept without being accessed.
+/// Configured by QUERY_CACHE_*_BYTES and QUERY_CACHE_TIME_TO_IDLE_SECONDS
+/// environment variables by default.
+#[derive(Clone, Debug)]
+pub struct CacheBudgets {
+    pub stores: u64,
+    pub graphs: u64,
+    pub formats: u64,
+    pub queries: u64,
+    pub time_to_idle: Duration,
+}
+
+impl Default for CacheBudgets {
+    fn default() -> Self {
+        CACHE_BUDGETS.clone()
+    }
 }
 
 impl QueryCache {
     pub fn new() -> Self {
+        Self::with_budgets(CacheBudgets::default())
+    }
+
+    /// Cache with entries weighted by their estimated size in bytes, evicted when a
+    /// cache exceeds its budget or an entry hasn't been accessed for time to idle.
+    pub fn with_budgets(budgets: CacheBudgets) -> Self {
         Self {
-            store_cache: Cache::new(1000),
-            graphs_cache: Cache::new(1000),
-            query_cache: Cache::new(1000),
+            store_cache: weighted_cache(
+                "stores",
+                budgets.stores,
+                budgets.time_to_idle,
+                |_, store: &CachedStore| match store {
+                    CachedStore::Memory { bytes, .. } => *bytes,
+                    CachedStore::Persisted(_) => ESTIMATED_PERSISTED_STORE_BYTES,
+                },
+            ),
+            graphs_cache: weighted_cache(
+                "graphs",
+                budgets.graphs,
+                budgets.time_to_idle,
+                |_, graphs: &String| graphs.len() as u64,
+            ),
+            formats_cache: weighted_cache(
+                "formats",
+                budgets.formats,
+                budgets.time_to_idle,
+                |_, graphs: &String| graphs.len() as u64,
+            ),
+            query_cache: weighted_cache(
+                "queries",
+                budgets.queries,
+                budgets.time_to_idle,
+                |(_, query, _): &(Option<Oid>, String, ResultFormats), result: &QueryResult| {
+                    (query.len() + result.body.len()) as u64
+                },
+            ),
+            persistent_stores: None,
+        }
+    }
+
+    /// Cache with stores persisted on disk. Previously persisted stores are opened
+    /// when first used.
+    pub fn with_persistent_stores(persistent_stores: PersistentStores) -> Result<Self, Error> {
+        persistent_stores.remove_incomplete()?;
+        Ok(Self {
+            persistent_stores: Some(Arc::new(persistent_stores)),
+            ..Self::new()
+        })
+    }
+
+    /// Cached store of a commit, opening it from disk if it's persisted but not open.
+    fn cached_store(&self, commit: Option<Oid>) -> Result<Option<CachedStore>, Error> {
+        if let Some(store) = self.store_cache.get(&commit) {
+            return Ok(Some(store));
         }
+        let (Some(persistent_stores), Some(commit)) = (&self.persistent_stores, commit) else {
+            return Ok(None);
+        };
+        Ok(persistent_stores.open(commit)?.map(|persisted| {
+            let store = CachedStore::Persisted(persisted);
+            self.store_cache.insert(Some(commit), store.clone());
+            store
+        }))
+    }
+
+    /// Cache the store of a commit and return the cached store, which is an on-disk
+    /// copy if stores are persisted.
+    pub fn insert_store(
+        &self,
+        commit: Option<Oid>,
+        store: oxigraph::store::Store,
+    ) -> Result<CachedStore, Error> {
+        let store = match (&self.persistent_stores, commit) {
+            (Some(persistent_stores), Some(commit)) => {
+                let _lock = persistent_stores.lock();
+                if let Some(cached) = self.store_cache.get(&Some(commit)) {
+                    return Ok(cached);
+                }
+                CachedStore::Persisted(self.persist_store(persistent_stores, commit, &store)?)
+            }
+            _ => CachedStore::Memory {
+                bytes: store.len()? as u64 * ESTIMATED_QUAD_BYTES,
+                store,
+            },
+        };
+        self.store_cache.insert(commit, store.clone());
+        Ok(store)
+    }
+
+    fn persist_store(
+        &self,
+        persistent_stores: &PersistentStores,
+        commit: Oid,
+        store: &oxigraph::store::Store,
+    ) -> Result<Arc<PersistedStore>, Error> {
+        let persisted = persistent_stores.persist(commit, store)?;
+        for evicted in persistent_stores.evict(commit)? {
+            self.store_cache.invalidate(&Some(evicted));
+        }
+        Ok(persisted)
+    }
+}
+
+impl QueryCache {
+    /// Number of entries and their estimated size in bytes, by cache type.
+    pub fn sizes(&self) -> [(&'static str, u64, u64); 4] {
+        let bytes = |weighted_size: u64| weighted_size * WEIGHT_UNIT_BYTES;
+        [
+            (
+                "graphs",
+                self.graphs_cache.entry_count(),
+                bytes(self.graphs_cache.weighted_size()),
+            ),
+            (
+                "formats",
+                self.formats_cache.entry_count(),
+                bytes(self.formats_cache.weighted_size()),
+            ),
+            (
+                "queries",
+                self.query_cache.entry_count(),
+                bytes(self.query_cache.weighted_size()),
+            ),
+            (
+                "stores",
+                self.store_cache.entry_count(),
+                bytes(self.store_cache.weighted_size()),
+            ),
+        ]
     }
 }
 
-/// Get graphs with cache. Return cache level alongside raw graph string.
+/// Store of a commit in the store cache, either in memory or persisted on disk.
+#[derive(Clone)]
+pub enum CachedStore {
+    /// Store in memory, with its estimated size in bytes. The size is estimated once
+    /// when cached, as counting the quads of a store scans all of them.
+    Memory {
+        store: oxigraph::store::Store,
+        bytes: u64,
+    },
+    Persisted(Arc<PersistedStore>),
+}
+
+impl Deref for CachedStore {
+    type Target = oxigraph::store::Store;
+
+    fn deref(&self) -> &oxigraph::store::Store {
+        match self {
+            CachedStore::Memory { store, .. } => store,
+            CachedStore::Persisted(store) => store,
+        }
+    }
+}
+
+/// Preload caches with the store of the latest commit, stores at the given
+/// timestamps, and results of the given queries for the latest commit.
+pub async fn warm_up<P: RdfPrettifier>(
+    rdf_prettifier: &P,
+    repo: &Repository,
+    cache: &QueryCache,
+    timestamps: &[u64],
+    queries: &[String],
+) -> Result<(), Error> {
+    let start_time = Instant::now();
+
+    let latest = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::String(e.to_string()))?
+        .as_secs();
+    let commit = commit_at_timestamp(repo, latest)?;
+    store_with_cache(repo, cache, commit).await?;
+
+    for timestamp in timestamps {
+        store_with_cache(repo, cache, commit_at_timestamp(repo, *timestamp)?).await?;
+    }
+    for query in queries {
+        query_with_cache(
+            rdf_prettifier,
+            repo,
+            cache,
+            latest,
+            query.clone(),
+            ResultFormats::default(),
+        )
+        .await?;
+    }
+
+    tracing::info!(
+        commit = commit.map(|oid| oid.to_string()),
+        elapsed_millis = start_time.elapsed().as_millis(),
+        "warmed up cache"
+    );
+    Ok(())
+}
+
+/// Cache weighted by estimated size in bytes, counting evictions by cache type.
+/// Entries larger than the budget are never cached, which is logged and counted as
+/// evictions of their own cause.
+fn weighted_cache<K, V>(
+    cache_type: &'static str,
+    max_bytes: u64,
+    time_to_idle: Duration,
+    bytes: impl Fn(&K, &V) -> u64 + Send + Sync + 'static,
+) -> Cache<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    let bytes = Arc::new(bytes);
+    let weigh = bytes.clone();
+    Cache::builder()
+        .max_capacity(max_bytes / WEIGHT_UNIT_BYTES)
+        .time_to_idle(time_to_idle)
+        .weigher(move |key, value| {
+            let bytes = weigh(key, value);
+            if bytes > max_bytes {
+                tracing::warn!(
+                    cache_type,
+                    bytes,
+                    max_bytes,
+                    "entry is larger than cache budget and is not cached"
+                );
+                CACHE_COUNT
+                    .with_label_values(&[cache_type, "evicted_too_large"])
+                    .inc();
+            }
+            bytes
+                .div_ceil(WEIGHT_UNIT_BYTES)
+                .try_into()
+                .unwrap_or(u32::MAX)
+        })
+        .eviction_listener(move |key, value, cause| {
+            let cause = match cause {
+                // Entries larger than the budget are counted when weighed.
+                RemovalCause::Size if bytes(&key, &value) > max_bytes => return,
+                RemovalCause::Size => "evicted_size",
+                RemovalCause::Expired => "evicted_expired",
+                // Explicitly removed or replaced entries are not evictions.
+                RemovalCause::Explicit | RemovalCause::Replaced => return,
+            };
+            CACHE_COUNT.with_label_values(&[cache_type, cause]).inc();
+        })
+        .build()
+}
+
+/// Maximum number of commits between a cached store and a requested commit, for the
+/// store of the requested commit to be built from the cached one.
+const MAX_INCREMENTAL_DISTANCE: usize = 100;
+
+/// Formats to serialize query results in, negotiated from the Accept header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ResultFormats {
+    /// Format of SELECT and ASK results, None if no supported format is acceptable.
+    pub solutions: Option<QueryResultsFormat>,
+    /// Format of CONSTRUCT and DESCRIBE results, None if no supported format is acceptable.
+    pub graph: Option<GraphFormat>,
+}
+
+impl Default for ResultFormats {
+    fn default() -> Self {
+        Self {
+            solutions: Some(QueryResultsFormat::Json),
+            graph: Some(GraphFormat::Turtle),
+        }
+    }
+}
+
+impl ResultFormats {
+    /// Negotiate formats from accepted media types, ordered by preference. Defaults
+    /// to JSON and Turtle if no media types are given.
+    pub fn from_media_types<'a>(media_types: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut media_types = media_types.into_iter().peekable();
+        if media_types.peek().is_none() {
+            return Self::default();
+        }
+
+        let mut formats = Self {
+            solutions: None,
+            graph: None,
+        };
+        for media_type in media_types {
+            let (solutions, graph) = match media_type {
+                "*/*" => (Some(QueryResultsFormat::Json), Some(GraphFormat::Turtle)),
+                "application/*" => (Some(QueryResultsFormat::Json), Some(GraphFormat::NTriples)),
+                "text/*" => (Some(QueryResultsFormat::Csv), Some(GraphFormat::Turtle)),
+                media_type => (
+                    QueryResultsFormat::from_media_type(media_type),
+                    GraphFormat::from_media_type(media_type),
+                ),
+            };
+            formats.solutions = formats.solutions.or(solutions);
+            formats.graph = formats.graph.or(graph);
+        }
+        formats
+    }
+}
+
+/// Serialized query result.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryResult {
+    pub body: String,
+    pub media_type: &'static str,
+}
+
+/// Get graphs with cache, serialized in format. Turtle is prettified. Return cache
+/// level alongside raw graph string.
 pub async fn graphs_with_cache<P: RdfPrettifier>(
     rdf_prettifier: &P,
     repo: &Repository,
     cache: &QueryCache,
     timestamp: u64,
+    format: SnapshotFormat,
 ) -> Result<(String, CacheLevel), Error> {
-    if let Some(graphs) = cache.graphs_cache.get(&timestamp) {
+    let commit = commit_at_timestamp(repo, timestamp)?;
+    if format != SnapshotFormat::Turtle {
+        return serialized_with_cache(repo, cache, commit, format).await;
+    }
+
+    if let Some(graphs) = cache.graphs_cache.get(&commit) {
         Ok((graphs, CacheLevel::Prettified))
-    } else if let Some(graph_store) = cache.store_cache.get(&timestamp) {
+    } else if let Some(graph_store) = cache.cached_store(commit)? {
         let turtle = to_turtle(&graph_store)?;
-        let prettified = rdf_prettifier.prettify(&turtle).await?;
+        let prettified = rdf_prettifier
+            .prettify(&turtle, RdfFormat::Graph(GraphFormat::Turtle))
+            .await?;
 
-        cache.graphs_cache.insert(timestamp, prettified.clone());
+        cache.graphs_cache.insert(commit, prettified.clone());
         Ok((prettified, CacheLevel::Graph))
     } else {
-        let graph_store = read_files_into_graph_store(repo, timestamp).await?;
+        let (graph_store, cache_lvl) = build_graph_store(repo, cache, commit).await?;
         let turtle = to_turtle(&graph_store)?;
-        let prettified = rdf_prettifier.prettify(&turtle).await?;
+        let prettified = rdf_prettifier
+            .prettify(&turtle, RdfFormat::Graph(GraphFormat::Turtle))
+            .await?;
 
-        cache.store_cache.insert(timestamp, graph_store);
-        cache.graphs_cache.insert(timestamp, prettified.clone());
-        Ok((prettified, CacheLevel::Nothing))
+        cache.insert_store(commit, graph_store)?;
+        cache.graphs_cache.insert(commit, prettified.clone());
+        Ok((prettified, cache_lvl))
     }
 }
 
-/// Query timestamp with cache. Return cache level alongside raw JSON result string.
+/// Get graphs serialized in a format other than prettified Turtle, with cache.
+async fn serialized_with_cache(
+    repo: &Repository,
+    cache: &QueryCache,
+    commit: Option<Oid>,
+    format: SnapshotFormat,
+) -> Result<(String, CacheLevel), Error> {
+    if let Some(graphs) = cache.formats_cache.get(&(commit, format)) {
+        return Ok((graphs, CacheLevel::Prettified));
+    }
+
+    let (graph_store, cache_lvl) = store_with_cache(repo, cache, commit).await?;
+    let serialized = serialize_store(&graph_store, format)?;
+
+    cache
+        .formats_cache
+        .insert((commit, format), serialized.clone());
+    Ok((serialized, cache_lvl))
+}
+
+/// Get a single graph as it was at a given timestamp, read directly from git and
+/// serialized in format. Return cache level alongside raw graph string.
+pub fn graph_at_timestamp(
+    repo: &Repository,
+    id: &str,
+    timestamp: u64,
+    format: SnapshotFormat,
+) -> Result<(String, CacheLevel), Error> {
+    let graph = read_graph_file(repo, id, timestamp)?.ok_or_else(|| {
+        Error::NotFound(format!(
+            "graph '{}' does not exist at timestamp {}",
+            id, timestamp
+        ))
+    })?;
+
+    // Graph files are already prettified Turtle.
+    if format == SnapshotFormat::Turtle {
+        return Ok((String::from_utf8(graph)?, CacheLevel::Nothing));
+    }
+
+    let name = graph_name(id);
+    let graph_name = match format {
+        SnapshotFormat::NQuads => GraphNameRef::NamedNode(name.as_ref()),
+        _ => GraphNameRef::DefaultGraph,
+    };
+    let store = oxigraph::store::Store::new()?;
+    store.load_graph(graph.as_slice(), GraphFormat::Turtle, graph_name, None)?;
+    Ok((serialize_store(&store, format)?, CacheLevel::Nothing))
+}
+
+/// Query timestamp with cache. Return cache level alongside serialized result.
 pub async fn query_with_cache<P: RdfPrettifier>(
     _rdf_prettifier: &P,
     repo: &Repository,
     cache: &QueryCache,
     timestamp: u64,
     query: String,
-) -> Result<(String, CacheLevel), Error> {
-    if let Some(query_result) = cache.query_cache.get(&(timestamp, query.clone())) {
+    formats: ResultFormats,
+) -> Result<(QueryResult, CacheLevel), Error> {
+    let commit = commit_at_timestamp(repo, timestamp)?;
+    let key = (commit, query, formats);
+    if let Some(query_result) = cache.query_cache.get(&key) {
         Ok((query_result, CacheLevel::Query))
-    } else if let Some(graph_store) = cache.store_cache.get(&timestamp) {
-        let query_result = execute_query_in_store(&graph_store, &query)?;
-        cache
-            .query_cache
-            .insert((timestamp, query), query_result.clone());
+    } else if let Some(graph_store) = cache.cached_store(commit)? {
+        let query_result = execute_query_in_store(&graph_store, &key.1, formats)?;
+        cache.query_cache.insert(key, query_result.clone());
 
         Ok((query_result, CacheLevel::Graph))
     } else {
-        let graph_store = read_files_into_graph_store(repo, timestamp).await?;
-        let query_result = execute_query_in_store(&graph_store, &query)?;
+        let (graph_store, cache_lvl) = build_graph_store(repo, cache, commit).await?;
+        let graph_store = cache.insert_store(commit, graph_store)?;
+        let query_result = execute_query_in_store(&graph_store, &key.1, formats)?;
 
-        cache.store_cache.insert(timestamp, graph_store);
-        cache
-            .query_cache
-            .insert((timestamp, query), query_result.clone());
+        cache.query_cache.insert(key, query_result.clone());
 
-        Ok((query_result, CacheLevel::Nothing))
+        Ok((query_result, cache_lvl))
     }
 }
 
-/// Execute sparql query in store and return JSON result as raw string.
-fn execute_query_in_store(store: &oxigraph::store::Store, query: &str) -> Result<String, Error> {
+/// Diff graphs between two timestamps with cache. Return cache level alongside diff.
+pub async fn diff_with_cache(
+    repo: &Repository,
+    cache: &QueryCache,
+    from: u64,
+    to: u64,
+) -> Result<(models::Diff, CacheLevel), Error> {
+    let from = commit_at_timestamp(repo, from)?;
+    let to = commit_at_timestamp(repo, to)?;
+    let (from_store, from_cache_lvl) = store_with_cache(repo, cache, from).await?;
+    let (to_store, to_cache_lvl) = store_with_cache(repo, cache, to).await?;
+    let (added, removed) = diff_stores(&from_store, &to_store)?;
+
+    let cache_lvl = match (from_cache_lvl, to_cache_lvl) {
+        (CacheLevel::Graph, CacheLevel::Graph) => CacheLevel::Graph,
+        _ => CacheLevel::Nothing,
+    };
+    Ok((models::Diff::new(added, removed), cache_lvl))
+}
+
+/// Diff a single graph between two timestamps, reading the graph files directly from
+/// git. A graph that exists at only one of the timestamps is diffed against nothing.
+pub fn graph_diff(repo: &Repository, id: &str, from: u64, to: u64) -> Result<models::Diff, Error> {
+    let from_graph = read_graph_file(repo, id, from)?;
+    let to_graph = read_graph_file(repo, id, to)?;
+    if from_graph.is_none() && to_graph.is_none() {
+        return Err(Error::NotFound(format!(
+            "graph '{}' does not exist at timestamp {} or {}",
+            id, from, to
+        )));
+    }
+
+    let from_store = read_file_into_graph_store(from_graph)?;
+    let to_store = read_file_into_graph_store(to_graph)?;
+    let (added, removed) = diff_stores(&from_store, &to_store)?;
+    Ok(models::Diff::new(added, removed))
+}
+
+/// Get graph store with cache, loading it from repo if not already cached.
+async fn store_with_cache(
+    repo: &Repository,
+    cache: &QueryCache,
+    commit: Option<Oid>,
+) -> Result<(CachedStore, CacheLevel), Error> {
+    if let Some(graph_store) = cache.cached_store(commit)? {
+        Ok((graph_store, CacheLevel::Graph))
+    } else {
+        let (graph_store, cache_lvl) = build_graph_store(repo, cache, commit).await?;
+        let graph_store = cache.insert_store(commit, graph_store)?;
+        Ok((graph_store, cache_lvl))
+    }
+}
+
+/// Execute sparql query in store and return result serialized in the negotiated format.
+fn execute_query_in_store(
+    store: &oxigraph::store::Store,
+    query: &str,
+    formats: ResultFormats,
+) -> Result<QueryResult, Error> {
+    let mut query = Query::parse(query, None).map_err(EvaluationError::from)?;
+    // Graphs are stored in named graphs, so the default graph is their union unless
+    // the query specifies a dataset.
+    if query.dataset().is_default_dataset() {
+        query.dataset_mut().set_default_graph_as_union();
+    }
+
     let start_time = Instant::now();
 
     let query_result = store.query(query)?;
@@ -107,36 +631,140 @@ fn execute_query_in_store(store: &oxigraph::store::Store, query: &str) -> Result
     let elapsed_millis = start_time.elapsed().as_millis();
     QUERY_PROCESSING_TIME.observe(elapsed_millis as f64 / 1000.0);
 
+    let not_acceptable =
+        || Error::NotAcceptable("no supported result format is acceptable".to_string());
     let mut results = Vec::new();
-    query_result.write(&mut results, QueryResultsFormat::Json)?;
-    let raw_json = String::from_utf8(results)?;
-    Ok(raw_json)
+    let media_type = match query_result {
+        QueryResults::Graph(_) => {
+            let format = formats.graph.ok_or_else(not_acceptable)?;
+            query_result.write_graph(&mut results, format)?;
+            format.media_type()
+        }
+        _ => {
+            let format = formats.solutions.ok_or_else(not_acceptable)?;
+            query_result.write(&mut results, format)?;
+            format.media_type()
+        }
+    };
+
+    Ok(QueryResult {
+        body: String::from_utf8(results)?,
+        media_type,
+    })
+}
+
+/// Build graph store with all graphs as of a commit. Built from the cached store of
+/// the nearest commit if there is one, otherwise loaded from the graph files.
+async fn build_graph_store(
+    repo: &Repository,
+    cache: &QueryCache,
+    commit: Option<Oid>,
+) -> Result<(oxigraph::store::Store, CacheLevel), Error> {
+    if let Some(commit) = commit {
+        if let Some((cached_commit, cached_store)) = nearest_cached_store(repo, cache, commit)? {
+            let store = apply_graph_changes(repo, &cached_store, cached_commit, commit)?;
+            return Ok((store, CacheLevel::Incremental));
+        }
+    }
+    let store = read_files_into_graph_store(repo, commit).await?;
+    Ok((store, CacheLevel::Nothing))
 }
 
-/// Load graph store with all graphs for a given timestamp.
+/// Find the cached store of the commit closest to a commit in history, if any is
+/// within MAX_INCREMENTAL_DISTANCE commits.
+fn nearest_cached_store(
+    repo: &Repository,
+    cache: &QueryCache,
+    commit: Oid,
+) -> Result<Option<(Oid, CachedStore)>, Error> {
+    let commits = list_commit_times(repo)?
+        .into_iter()
+        .map(|(_, oid)| oid)
+        .collect::<Vec<Oid>>();
+    let index = match commits.iter().position(|oid| *oid == commit) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+
+    for distance in 1..=MAX_INCREMENTAL_DISTANCE {
+        let neighbours = [index.checked_sub(distance), Some(index + distance)];
+        for neighbour in neighbours.into_iter().flatten() {
+            if let Some(oid) = commits.get(neighbour) {
+                if let Some(store) = cache.cached_store(Some(*oid))? {
+                    return Ok(Some((*oid, store)));
+                }
+            }
+        }
+        if distance > index && index + distance >= commits.len() {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Build graph store as of commit `to` from the store of commit `from`, by replacing
+/// the named graphs of graphs that changed between them. The store of `from` is left
+/// unchanged, so every quad is copied into a new store. Stores share no data to copy
+/// on write, but copying quads is still much cheaper than parsing every graph file.
+fn apply_graph_changes(
+    repo: &Repository,
+    from_store: &oxigraph::store::Store,
+    from: Oid,
+    to: Oid,
+) -> Result<oxigraph::store::Store, Error> {
+    let start_time = Instant::now();
+
+    let store = oxigraph::store::Store::new()?;
+    // Streamed rather than collected, to not hold a second copy of every quad.
+    store
+        .bulk_loader()
+        .load_ok_quads::<_, Error>(from_store.iter())?;
+
+    for (id, graph) in changed_graphs(repo, from, to)? {
+        let name = graph_name(&id);
+        store.clear_graph(GraphNameRef::NamedNode(name.as_ref()))?;
+        if let Some(graph) = graph {
+            store.load_graph(
+                graph.as_slice(),
+                GraphFormat::Turtle,
+                GraphNameRef::NamedNode(name.as_ref()),
+                None,
+            )?;
+        }
+    }
+
+    let elapsed_millis = start_time.elapsed().as_millis();
+    GRAPH_PARSE_TIME.observe(elapsed_millis as f64 / 1000.0);
+
+    Ok(store)
+}
+
+/// Load graph store with all graphs as of a commit, each in a named graph derived
+/// from its id.
 async fn read_files_into_graph_store(
     repo: &Repository,
-    timestamp: u64,
+    commit: Option<Oid>,
 ) -> Result<oxigraph::store::Store, Error> {
     let store = oxigraph::store::Store::new()?;
 
-    let graphs = read_all_graph_files(repo, timestamp).await?;
-    if graphs.len() == 0 {
+    let graphs = read_all_graph_files_at_commit(repo, commit).await?;
+    if graphs.is_empty() {
         return Ok(store);
     }
 
-    // TODO: try to simply join all graphs without separating prefix and data.
-    let graph = combine_graphs(graphs)?;
     let start_time = Instant::now();
 
-    // Combining all graphs into a single one and using bulk loader is a bit
-    // faster than parsing one at a time (with or without bulk loader).
-    store.bulk_loader().load_graph(
-        graph.as_ref(),
-        GraphFormat::Turtle,
-        GraphNameRef::DefaultGraph,
-        None,
-    )?;
+    let mut quads = Vec::new();
+    for (id, graph) in graphs {
+        let name = graph_name(&id);
+        for triple in
+            GraphParser::from_format(GraphFormat::Turtle).read_triples(graph.as_slice())?
+        {
+            quads.push(triple?.in_graph(name.clone()));
+        }
+    }
+    // Bulk loading all graphs at once is a bit faster than loading one at a time.
+    store.bulk_loader().load_quads(quads)?;
 
     let elapsed_millis = start_time.elapsed().as_millis();
     GRAPH_PARSE_TIME.observe(elapsed_millis as f64 / 1000.0);
@@ -144,26 +772,16 @@ async fn read_files_into_graph_store(
     Ok(store)
 }
 
-/// Combine multiple graphs into one single graph (merging prefix sections and data separately).
-fn combine_graphs(graphs: Vec<Vec<u8>>) -> Result<String, Error> {
-    let graph_strs = graphs
-        .into_iter()
-        .map(|graph| String::from_utf8(graph))
-        .collect::<Result<Vec<String>, FromUtf8Error>>()?;
-
-    let graph_count = graph_strs.len();
-    let mut prefix_merge = Vec::with_capacity(graph_count);
-    let mut graph_merge = Vec::with_capacity(graph_count);
-
-    for i in 0..graph_strs.len() {
-        let split = graph_strs[i].split_once("\n");
-        if let Some((prefix_part, graph_part)) = split {
-            prefix_merge.push(prefix_part);
-            graph_merge.push(graph_part);
-        }
+/// Load graph store with a single graph, or an empty store if there is no graph.
+fn read_file_into_graph_store(graph: Option<Vec<u8>>) -> Result<oxigraph::store::Store, Error> {
+    let store = oxigraph::store::Store::new()?;
+    if let Some(graph) = graph {
+        store.load_graph(
+            graph.as_slice(),
+            GraphFormat::Turtle,
+            GraphNameRef::DefaultGraph,
+            None,
+        )?;
     }
-
-    let mut combined = prefix_merge;
-    combined.append(&mut graph_merge);
-    Ok(combined.join("\n"))
+    Ok(store)
 }
@@ -1,14 +1,31 @@
-use std::{env, time::Instant};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    time::Instant,
+};
 
 use async_trait::async_trait;
 use lazy_static::lazy_static;
-use oxigraph::{io::GraphFormat, model::GraphNameRef};
+use oxigraph::{
+    io::{DatasetFormat, GraphFormat, GraphParser, GraphSerializer},
+    model::{
+        vocab::{rdf, xsd},
+        GraphName, Subject, Term, Triple,
+    },
+};
 use reqwest::StatusCode;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 
-use crate::{error::Error, metrics::RDF_PRETTIFIER_TIME};
+use crate::{
+    canonical::{canonical_turtle, canonicalize_blank_nodes},
+    error::Error,
+    metrics::RDF_PRETTIFIER_TIME,
+};
 
 lazy_static! {
+    static ref RDF_PRETTIFIER: String =
+        env::var("RDF_PRETTIFIER").unwrap_or_else(|_| "api".to_string());
     static ref RDF_PRETTIFIER_URL: String = env::var("RDF_PRETTIFIER_URL").unwrap_or_else(|e| {
         tracing::error!(
             error = e.to_string().as_str(),
@@ -26,12 +43,95 @@ lazy_static! {
         });
 }
 
+/// RDF serialization formats accepted when storing graphs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RdfFormat {
+    Graph(GraphFormat),
+    JsonLd,
+}
+
+impl RdfFormat {
+    /// Look up format from media type. Graphs without format are Turtle.
+    pub fn from_media_type(media_type: Option<&str>) -> Result<Self, Error> {
+        let media_type = match media_type {
+            Some(media_type) => media_type,
+            None => return Ok(RdfFormat::Graph(GraphFormat::Turtle)),
+        };
+
+        if let Some(format) = GraphFormat::from_media_type(media_type) {
+            Ok(RdfFormat::Graph(format))
+        } else if media_type.split(';').next().map(str::trim) == Some("application/ld+json") {
+            Ok(RdfFormat::JsonLd)
+        } else {
+            Err(Error::BadRequest(format!(
+                "unsupported graph format: {}",
+                media_type
+            )))
+        }
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            RdfFormat::Graph(format) => format.media_type(),
+            RdfFormat::JsonLd => "application/ld+json",
+        }
+    }
+}
+
+/// RDF serialization formats graph snapshots can be returned in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SnapshotFormat {
+    Turtle,
+    NTriples,
+    RdfXml,
+    JsonLd,
+    /// N-Quads, with each graph in a named graph derived from its id.
+    NQuads,
+}
+
+impl SnapshotFormat {
+    /// Negotiate format from accepted media types, ordered by preference. Defaults to
+    /// Turtle if no media types are given, and None if none of them are supported.
+    pub fn from_media_types<'a>(media_types: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        let mut media_types = media_types.into_iter().peekable();
+        if media_types.peek().is_none() {
+            return Some(SnapshotFormat::Turtle);
+        }
+
+        media_types.find_map(|media_type| match media_type {
+            "*/*" | "text/*" => Some(SnapshotFormat::Turtle),
+            "application/*" => Some(SnapshotFormat::NTriples),
+            "application/ld+json" => Some(SnapshotFormat::JsonLd),
+            "application/n-quads" | "text/x-nquads" | "text/nquads" => Some(SnapshotFormat::NQuads),
+            media_type => match GraphFormat::from_media_type(media_type) {
+                Some(GraphFormat::Turtle) => Some(SnapshotFormat::Turtle),
+                Some(GraphFormat::NTriples) => Some(SnapshotFormat::NTriples),
+                Some(GraphFormat::RdfXml) => Some(SnapshotFormat::RdfXml),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Turtle => GraphFormat::Turtle.media_type(),
+            SnapshotFormat::NTriples => GraphFormat::NTriples.media_type(),
+            SnapshotFormat::RdfXml => GraphFormat::RdfXml.media_type(),
+            SnapshotFormat::JsonLd => "application/ld+json",
+            SnapshotFormat::NQuads => DatasetFormat::NQuads.media_type(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait RdfPrettifier {
     fn new() -> Self;
-    async fn prettify(&self, graph: &str) -> Result<String, Error>;
+    /// Prettify graph of given format, returning it as Turtle.
+    async fn prettify(&self, graph: &str, format: RdfFormat) -> Result<String, Error>;
 }
 
+/// Prettifier calling the RDF prettifier API, which parses the graph in its own
+/// format, JSON-LD included, and returns it as Turtle.
 #[derive(Clone)]
 pub struct APIPrettifier(reqwest::Client);
 
@@ -41,7 +141,7 @@ impl RdfPrettifier for APIPrettifier {
         APIPrettifier(reqwest::Client::new())
     }
 
-    async fn prettify(&self, graph: &str) -> Result<String, Error> {
+    async fn prettify(&self, graph: &str, format: RdfFormat) -> Result<String, Error> {
         let retry_attempts = 5;
         let response = {
             let mut r = Err(Error::String(
@@ -54,13 +154,13 @@ impl RdfPrettifier for APIPrettifier {
                     .post(RDF_PRETTIFIER_URL.clone())
                     .header("X-API-KEY", RDF_PRETTIFIER_API_KEY.clone())
                     .json(&json!({
-                        "format": "text/turtle",
+                        "format": format.media_type(),
                         "output_format": "text/turtle",
                         "graph": graph,
                     }))
                     .send()
                     .await
-                    .map_err(|e| Error::from(e));
+                    .map_err(Error::from);
 
                 if r.is_ok() {
                     let elapsed_millis = start_time.elapsed().as_millis();
@@ -73,6 +173,10 @@ impl RdfPrettifier for APIPrettifier {
 
         match response.status() {
             StatusCode::OK => Ok(response.text().await?),
+            // The API is not able to parse the graph in the given format.
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Err(Error::BadRequest(
+                format!("invalid graph: {}", response.text().await?),
+            )),
             _ => Err(format!(
                 "invalid response from pretty print api: {} - {}",
                 response.status(),
@@ -83,10 +187,277 @@ impl RdfPrettifier for APIPrettifier {
     }
 }
 
+/// Prettifier that canonicalizes graphs in-process, without any external service.
+/// JSON-LD is not supported, as oxigraph is not able to parse it.
+#[derive(Clone)]
+pub struct LocalPrettifier;
+
+#[async_trait]
+impl RdfPrettifier for LocalPrettifier {
+    fn new() -> Self {
+        LocalPrettifier
+    }
+
+    async fn prettify(&self, graph: &str, format: RdfFormat) -> Result<String, Error> {
+        let start_time = Instant::now();
+
+        let triples = match format {
+            RdfFormat::Graph(format) => parse_graph(graph, format)?,
+            RdfFormat::JsonLd => {
+                return Err(Error::UnsupportedMediaType(
+                    "JSON-LD is not supported by local prettifier".to_string(),
+                ))
+            }
+        };
+        let turtle = canonical_turtle(triples, "c14n")?;
+
+        let elapsed_millis = start_time.elapsed().as_millis();
+        RDF_PRETTIFIER_TIME.observe(elapsed_millis as f64 / 1000.0);
+
+        Ok(turtle)
+    }
+}
+
+/// Prettifier selected by RDF_PRETTIFIER environment variable, either `api` (default) or `local`.
+#[derive(Clone)]
+pub enum ConfiguredPrettifier {
+    Api(APIPrettifier),
+    Local(LocalPrettifier),
+}
+
+#[async_trait]
+impl RdfPrettifier for ConfiguredPrettifier {
+    fn new() -> Self {
+        match RDF_PRETTIFIER.as_str() {
+            "local" => ConfiguredPrettifier::Local(LocalPrettifier::new()),
+            "api" => ConfiguredPrettifier::Api(APIPrettifier::new()),
+            other => {
+                tracing::error!(prettifier = other, "invalid RDF_PRETTIFIER");
+                std::process::exit(1)
+            }
+        }
+    }
+
+    async fn prettify(&self, graph: &str, format: RdfFormat) -> Result<String, Error> {
+        match self {
+            ConfiguredPrettifier::Api(prettifier) => prettifier.prettify(graph, format).await,
+            ConfiguredPrettifier::Local(prettifier) => prettifier.prettify(graph, format).await,
+        }
+    }
+}
+
+/// Relabel blank nodes of a Turtle graph canonically, so that a graph is serialized
+/// identically regardless of the blank node labels it was received with. Labels are
+/// scoped by graph id, as graph files are combined when loaded into a store. Graphs
+/// without blank nodes are returned unchanged.
+pub fn canonicalize_graph(graph: String, id: &str) -> Result<String, Error> {
+    let triples = parse_graph(&graph, GraphFormat::Turtle)?;
+    if !triples.iter().any(has_blank_node) {
+        return Ok(graph);
+    }
+    canonical_turtle(triples, &blank_node_prefix(id))
+}
+
+fn has_blank_node(triple: &Triple) -> bool {
+    triple.subject.is_blank_node() || triple.object.is_blank_node()
+}
+
+/// Prefix of canonical blank node labels of a graph, unique per graph.
+fn blank_node_prefix(graph: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(graph.as_bytes()));
+    format!("g{}c14n", &hash[..12])
+}
+
+/// Whether two Turtle graphs contain the same triples, up to blank node labels.
+pub fn isomorphic_graphs(graph: &str, other: &str) -> Result<bool, Error> {
+    let triples = parse_graph(graph, GraphFormat::Turtle)?;
+    let other_triples = parse_graph(other, GraphFormat::Turtle)?;
+    Ok(canonical_triples(triples, "c14n")? == canonical_triples(other_triples, "c14n")?)
+}
+
+/// Set of triples as N-Triples lines, with blank nodes labelled `{prefix}{n}`
+/// canonically.
+fn canonical_triples(triples: Vec<Triple>, prefix: &str) -> Result<BTreeSet<String>, Error> {
+    Ok(canonicalize_blank_nodes(triples, prefix)?
+        .iter()
+        .map(|triple| format!("{} .\n", triple))
+        .collect())
+}
+
+/// Returns an error if graph is not valid RDF of the given format. JSON-LD is only
+/// checked to be valid JSON, as oxigraph is not able to parse it. It is parsed into
+/// RDF by the prettifier, and the Turtle it returns is parsed again when blank nodes
+/// are canonicalized, so a graph is never stored without being valid RDF.
+pub fn validate_graph(graph: &str, format: RdfFormat) -> Result<(), Error> {
+    match format {
+        RdfFormat::Graph(format) => {
+            parse_graph(graph, format)?;
+        }
+        RdfFormat::JsonLd => {
+            serde_json::from_str::<serde_json::Value>(graph).map_err(|e| invalid_graph(&e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse all triples of graph.
+pub fn parse_graph(graph: &str, format: GraphFormat) -> Result<Vec<Triple>, Error> {
+    GraphParser::from_format(format)
+        .read_triples(graph.as_bytes())
+        .map_err(|e| invalid_graph(&e))?
+        .map(|triple| triple.map_err(|e| invalid_graph(&e)))
+        .collect()
+}
+
+fn invalid_graph(e: &dyn std::error::Error) -> Error {
+    Error::BadRequest(format!("invalid graph: {}", e))
+}
+
+/// Serialize all triples in store in format. Triples of all graphs are merged,
+/// except for N-Quads where the whole dataset is serialized.
+pub fn serialize_store(
+    store: &oxigraph::store::Store,
+    format: SnapshotFormat,
+) -> Result<String, Error> {
+    let graph_format = match format {
+        SnapshotFormat::Turtle => GraphFormat::Turtle,
+        SnapshotFormat::NTriples => GraphFormat::NTriples,
+        SnapshotFormat::RdfXml => GraphFormat::RdfXml,
+        SnapshotFormat::JsonLd => return Ok(to_jsonld(&union_graph(store)?)?.to_string()),
+        SnapshotFormat::NQuads => {
+            let mut buff = Vec::new();
+            store.dump_dataset(&mut buff, DatasetFormat::NQuads)?;
+            return Ok(String::from_utf8(buff)?);
+        }
+    };
+    serialize_triples(&union_graph(store)?, graph_format)
+}
+
+/// Distinct triples of all graphs in store, the default graph included.
+pub fn union_graph(store: &oxigraph::store::Store) -> Result<Vec<Triple>, Error> {
+    let mut seen = HashSet::new();
+    let mut triples = Vec::new();
+    for quad in store.iter() {
+        let triple = Triple::from(quad?);
+        if seen.insert(triple.clone()) {
+            triples.push(triple);
+        }
+    }
+    Ok(triples)
+}
+
+/// Triples as expanded JSON-LD, with one node object per subject. Quoted triples
+/// are not supported by JSON-LD 1.1, so graphs using RDF-star are not acceptable.
+fn to_jsonld(triples: &[Triple]) -> Result<serde_json::Value, Error> {
+    let quoted_triple = || {
+        Error::NotAcceptable(
+            "graphs with quoted triples can't be serialized as JSON-LD".to_string(),
+        )
+    };
+    let node_id = |subject: &Subject| match subject {
+        Subject::NamedNode(node) => Ok(node.as_str().to_string()),
+        Subject::BlankNode(node) => Ok(format!("_:{}", node.as_str())),
+        Subject::Triple(_) => Err(quoted_triple()),
+    };
+
+    let mut nodes: BTreeMap<String, BTreeMap<String, Vec<serde_json::Value>>> = BTreeMap::new();
+    for triple in triples {
+        let properties = nodes.entry(node_id(&triple.subject)?).or_default();
+        if triple.predicate.as_ref() == rdf::TYPE {
+            if let Term::NamedNode(class) = &triple.object {
+                properties
+                    .entry("@type".to_string())
+                    .or_default()
+                    .push(json!(class.as_str()));
+                continue;
+            }
+        }
+
+        let object = match &triple.object {
+            Term::NamedNode(node) => json!({ "@id": node.as_str() }),
+            Term::BlankNode(node) => json!({ "@id": format!("_:{}", node.as_str()) }),
+            Term::Literal(literal) => match literal.language() {
+                Some(language) => json!({ "@value": literal.value(), "@language": language }),
+                None if literal.datatype() == xsd::STRING => json!({ "@value": literal.value() }),
+                None => json!({ "@value": literal.value(), "@type": literal.datatype().as_str() }),
+            },
+            Term::Triple(_) => return Err(quoted_triple()),
+        };
+        properties
+            .entry(triple.predicate.as_str().to_string())
+            .or_default()
+            .push(object);
+    }
+
+    Ok(serde_json::Value::Array(
+        nodes
+            .into_iter()
+            .map(|(id, properties)| {
+                let mut node = serde_json::Map::new();
+                node.insert("@id".to_string(), json!(id));
+                for (property, values) in properties {
+                    node.insert(property, serde_json::Value::Array(values));
+                }
+                serde_json::Value::Object(node)
+            })
+            .collect(),
+    ))
+}
 
 pub fn to_turtle(store: &oxigraph::store::Store) -> Result<String, Error> {
+    serialize_triples(&union_graph(store)?, GraphFormat::Turtle)
+}
+
+/// Triples in `to` that are not in `from` (added) and triples in `from` that are
+/// not in `to` (removed), both serialized as N-Triples. Triples of all graphs are
+/// compared, so a triple is only added or removed if no graph had it before or has
+/// it after.
+pub fn diff_stores(
+    from: &oxigraph::store::Store,
+    to: &oxigraph::store::Store,
+) -> Result<(String, String), Error> {
+    let from_triples = snapshot_triples(from)?;
+    let to_triples = snapshot_triples(to)?;
+    Ok((
+        to_triples.difference(&from_triples).cloned().collect(),
+        from_triples.difference(&to_triples).cloned().collect(),
+    ))
+}
+
+/// Distinct triples of all graphs in store, as N-Triples lines. Blank nodes are
+/// relabelled whenever a graph is parsed, so triples with blank nodes are given
+/// canonical labels per graph, scoped by graph so that blank nodes of different
+/// graphs are never confused.
+fn snapshot_triples(store: &oxigraph::store::Store) -> Result<BTreeSet<String>, Error> {
+    let mut triples = BTreeSet::new();
+    let mut blank_node_triples: HashMap<GraphName, Vec<Triple>> = HashMap::new();
+    for quad in store.iter() {
+        let quad = quad?;
+        let graph_name = quad.graph_name.clone();
+        let triple = Triple::from(quad);
+        if has_blank_node(&triple) {
+            blank_node_triples
+                .entry(graph_name)
+                .or_default()
+                .push(triple);
+        } else {
+            triples.insert(format!("{} .\n", triple));
+        }
+    }
+
+    for (graph_name, graph_triples) in blank_node_triples {
+        let prefix = blank_node_prefix(&graph_name.to_string());
+        triples.extend(canonical_triples(graph_triples, &prefix)?);
+    }
+    Ok(triples)
+}
+
+fn serialize_triples(triples: &[Triple], format: GraphFormat) -> Result<String, Error> {
     let mut buff = Vec::new();
-    store.dump_graph(&mut buff, GraphFormat::Turtle, GraphNameRef::DefaultGraph)?;
-    let turtle = String::from_utf8(buff)?;
-    Ok(turtle)
+    let mut writer = GraphSerializer::from_format(format).triple_writer(&mut buff)?;
+    for triple in triples {
+        writer.write(triple)?;
+    }
+    writer.finish()?;
+    Ok(String::from_utf8(buff)?)
 }
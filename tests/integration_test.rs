@@ -1,12 +1,45 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use actix_web::{
+    http::{header, StatusCode},
+    middleware::ErrorHandlers,
+    web, App, HttpRequest, HttpResponse, HttpServer, ResponseError,
+};
+use oxigraph::{
+    io::GraphFormat,
+    model::{GraphName, GraphNameRef, Literal, NamedNode, Quad, Triple},
+    sparql::{QueryOptions, QueryResults, QueryResultsFormat},
+};
 use rdf_diff_store::{
-    git::{checkout_main_and_fetch_updates, list_commit_times, push_updates, ReusableRepoPool},
-    graphs::{read_all_graph_files, store_graph},
-    models::Graph,
-    rdf::RdfPrettifier,
+    api::{
+        accepted_media_types, extractor_config, fetch_in_background, parse_graphs, payload_config,
+        post_api_refresh, problem_instance, State,
+    },
+    error::{Error, PROBLEM_JSON},
+    git::{
+        checkout_main_and_fetch_updates, commit_at_timestamp, list_commit_times, push_updates,
+        ReusableRepoPool,
+    },
+    graphs::{
+        delete_graph, graph_filename, graph_history, graph_name, read_all_graph_files,
+        replace_graphs, store_graph, store_graphs, GraphComparison,
+    },
+    metrics::CACHE_COUNT,
+    models::{self, graph_change::Operation, graph_status::Status, Graph},
+    persistence::PersistentStores,
+    query::{
+        diff_with_cache, graph_at_timestamp, graph_diff, graphs_with_cache, query_with_cache,
+        warm_up, CacheBudgets, CacheLevel, QueryCache, ResultFormats,
+    },
+    rdf::{
+        diff_stores, serialize_store, validate_graph, APIPrettifier, ConfiguredPrettifier,
+        LocalPrettifier, RdfFormat, RdfPrettifier, SnapshotFormat,
+    },
+};
+use utils::{
+    api_key, clone_repo_pool, create_repo_pool, graph, new_repo_pool, push_graph,
+    timestamp_after_sleep, titled_graph, wait_until, NoOpPrettifier,
 };
-use utils::{create_repo_pool, NoOpPrettifier};
 
 mod utils;
 
@@ -16,7 +49,7 @@ mod utils;
 #[tokio::test]
 async fn timestamps() {
     let repo_pool = create_repo_pool("timestamps", 2).await;
-    let push_repo = ReusableRepoPool::pop(&repo_pool).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
 
     let mut graph = Graph {
         id: "<#/(%¤=:".to_string(),
@@ -65,7 +98,7 @@ async fn timestamps() {
         + 1;
 
     // Use another repo from the pool to get graphs, to assert that fetch/pull works.
-    let pull_repo = ReusableRepoPool::pop(&repo_pool).await;
+    let pull_repo = repo_pool.get().await.expect("unable to get repo from pool");
     checkout_main_and_fetch_updates(&pull_repo).expect("unable to checkout main and fetch");
 
     // The following order (post -> pre -> mid) is chosen to test that the repo
@@ -88,15 +121,137 @@ async fn timestamps() {
         .await
         .expect("unable to read graphs");
     assert_eq!(graphs_mid.len(), 1);
+}
+
+/// Read graphs at earlier timestamps, and check that reads don't check out commits,
+/// so HEAD stays on main, no branches are created and the working tree is unchanged.
+#[tokio::test]
+async fn reads_leave_head_on_main() {
+    let repo_pool = create_repo_pool("reads-head", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph("a"))
+        .await
+        .expect("unable to store graph");
+    let mid_time = timestamp_after_sleep();
+    store_graph(&repo, &NoOpPrettifier::new(), &graph("b"))
+        .await
+        .expect("unable to store graph");
+
+    let graphs = read_all_graph_files(&repo, mid_time)
+        .await
+        .expect("unable to read graphs");
+    assert_eq!(graphs.len(), 1);
+    graph_at_timestamp(&repo, "a", mid_time, SnapshotFormat::Turtle).expect("unable to get graph");
+
+    let head = repo.head().expect("unable to get head");
+    assert_eq!(head.name(), Some("refs/heads/main"));
+    let branches = repo
+        .branches(Some(git2::BranchType::Local))
+        .expect("unable to list branches")
+        .count();
+    assert_eq!(branches, 1);
 
-    ReusableRepoPool::push(&repo_pool, pull_repo).await;
-    ReusableRepoPool::push(&repo_pool, push_repo).await;
+    // Both graphs are still in the working tree, as the latest commit is never left.
+    let workdir = repo.workdir().expect("repo has no working tree");
+    assert!(workdir.join(graph_filename("a")).is_file());
+    assert!(workdir.join(graph_filename("b")).is_file());
+}
+
+/// Check that repos in pool are handles to the same clone, so that a commit made
+/// through one of them is seen by the others without fetching.
+#[tokio::test]
+async fn shared_clone() {
+    let repo_pool = create_repo_pool("shared-clone", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    let other_repo = repo_pool.get().await.expect("unable to get repo from pool");
+    assert_eq!(repo.path(), other_repo.path());
+
+    let graph = Graph {
+        id: "shared".to_string(),
+        graph: r#"<https://example.com/a> <http://purl.org/dc/terms/title> "A" ."#.to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    let commit_times = list_commit_times(&other_repo).expect("unable to list commits");
+    assert_eq!(commit_times.len(), 1);
+}
+
+/// Check that creating a pool removes copies of its clone made by earlier versions of
+/// the pool, but leaves other folders next to the clone alone.
+#[tokio::test]
+async fn repo_copies() {
+    let repo_pool = new_repo_pool("repo-copies", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    let url = repo
+        .find_remote("origin")
+        .expect("unable to find origin")
+        .url()
+        .expect("invalid origin url")
+        .to_string();
+    let root_path = repo
+        .workdir()
+        .and_then(|workdir| workdir.parent())
+        .expect("clone has no parent folder")
+        .to_path_buf();
+    drop(repo);
+
+    let copy_path = root_path.join("1");
+    git2::Repository::clone(&url, &copy_path).expect("unable to copy clone");
+    let other_path = root_path.join("2");
+    std::fs::create_dir(&other_path).expect("unable to create folder");
+    std::fs::write(other_path.join("data"), "data").expect("unable to write file");
+
+    ReusableRepoPool::new(url, root_path.to_string_lossy().to_string(), 1)
+        .expect("unable to create repo pool");
+    assert!(!copy_path.exists());
+    assert!(other_path.join("data").is_file());
+}
+
+/// Store a graph and update it, and check that the graph is returned as it was at
+/// each timestamp, and not found before it was created.
+#[tokio::test]
+async fn single_graph_at_timestamp() {
+    let repo_pool = create_repo_pool("single-graph", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    let pre_time = timestamp_after_sleep();
+    store_graph(&repo, &NoOpPrettifier::new(), &titled_graph("single", "A"))
+        .await
+        .expect("unable to store graph");
+    let mid_time = timestamp_after_sleep();
+    store_graph(&repo, &NoOpPrettifier::new(), &titled_graph("single", "B"))
+        .await
+        .expect("unable to store graph");
+    let post_time = timestamp_after_sleep();
+
+    let (mid, _) = graph_at_timestamp(&repo, "single", mid_time, SnapshotFormat::Turtle)
+        .expect("unable to get graph");
+    assert_eq!(mid, titled_graph("single", "A").graph);
+    let (post, _) = graph_at_timestamp(&repo, "single", post_time, SnapshotFormat::Turtle)
+        .expect("unable to get graph");
+    assert_eq!(post, titled_graph("single", "B").graph);
+
+    assert!(matches!(
+        graph_at_timestamp(&repo, "single", pre_time, SnapshotFormat::Turtle),
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(
+        graph_at_timestamp(&repo, "missing", post_time, SnapshotFormat::Turtle),
+        Err(Error::NotFound(_))
+    ));
 }
 
 #[tokio::test]
 async fn test_no_diff() {
     let repo_pool = create_repo_pool("no-diff", 2).await;
-    let push_repo = ReusableRepoPool::pop(&repo_pool).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
 
     let graph = Graph {
         id: "duplicate".to_string(),
@@ -120,6 +275,1731 @@ async fn test_no_diff() {
 
     let commit_times = list_commit_times(&push_repo).expect("unable to list commits");
     assert_eq!(commit_times.len(), 1);
+}
+
+/// Store a graph, then replace it with a modified version, and check that the
+/// diff between the two timestamps contains exactly the changed triples, both
+/// for all graphs and for the single graph.
+#[tokio::test]
+async fn diff() {
+    let repo_pool = create_repo_pool("diff", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let mut graph = Graph {
+        id: "diffed".to_string(),
+        graph: r#"
+        @prefix si: <https://www.w3schools.com/rdf/> .
+
+        <https://www.w3schools00.com> si:author "Jan Egil Refsnes" ;
+            si:title "W3Schools" .
+        "#
+        .to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    std::thread::sleep(Duration::from_secs(1));
+
+    let mid_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+
+    std::thread::sleep(Duration::from_secs(1));
+
+    graph.graph = r#"
+        @prefix si: <https://www.w3schools.com/rdf/> .
+
+        <https://www.w3schools00.com> si:author "Jan Egil Refsnes" ;
+            si:title "W3Schools.com" .
+        "#
+    .to_string();
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+
+    let (diff, _) = diff_with_cache(&repo, &QueryCache::new(), mid_time, post_time)
+        .await
+        .expect("unable to diff graphs");
+    assert_eq!(
+        diff.added,
+        "<https://www.w3schools00.com> <https://www.w3schools.com/rdf/title> \"W3Schools.com\" .\n"
+    );
+    assert_eq!(
+        diff.removed,
+        "<https://www.w3schools00.com> <https://www.w3schools.com/rdf/title> \"W3Schools\" .\n"
+    );
+
+    // Diff of the single graph should be the same, as it is the only graph.
+    let graph_diff =
+        graph_diff(&repo, "diffed", mid_time, post_time).expect("unable to diff graph");
+    assert_eq!(graph_diff, diff);
+}
+
+/// Update a graph twice and delete it, and check that its history lists each
+/// change in order, while changes to other graphs are left out.
+#[tokio::test]
+async fn history() {
+    let repo_pool = create_repo_pool("history", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    for graph in [graph("a"), graph("b"), titled_graph("a", "A2")] {
+        store_graph(&repo, &NoOpPrettifier::new(), &graph)
+            .await
+            .expect("unable to store graph");
+    }
+    delete_graph(&repo, "a".to_string())
+        .await
+        .expect("unable to delete graph");
+
+    let history = graph_history(&repo, "a").expect("unable to list graph history");
+    assert_eq!(
+        history
+            .iter()
+            .map(|change| change.operation)
+            .collect::<Vec<_>>(),
+        vec![Operation::Update, Operation::Update, Operation::Delete]
+    );
+    assert!(history
+        .windows(2)
+        .all(|changes| changes[0].timestamp <= changes[1].timestamp));
+
+    let history = graph_history(&repo, "missing").expect("unable to list graph history");
+    assert!(history.is_empty());
+}
+
+/// Diff a single graph that is created between the two timestamps, and check that a
+/// graph that exists at neither timestamp is not found.
+#[tokio::test]
+async fn graph_diff_not_found() {
+    let repo_pool = create_repo_pool("graph-diff-not-found", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let pre_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        - 1;
+
+    let graph = Graph {
+        id: "created".to_string(),
+        graph: r#"<https://example.com/a> <http://purl.org/dc/terms/title> "A" ."#.to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+
+    let diff = graph_diff(&repo, "created", pre_time, post_time).expect("unable to diff graph");
+    assert_eq!(
+        diff.added,
+        "<https://example.com/a> <http://purl.org/dc/terms/title> \"A\" .\n"
+    );
+    assert_eq!(diff.removed, "");
+
+    let not_found =
+        graph_diff(&repo, "missing", pre_time, post_time).expect_err("diffed missing graph");
+    assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+}
+
+/// Store a graph with blank nodes, then change it and change it back, and check
+/// that the diff between the first and last snapshot is empty, even though blank
+/// nodes are relabelled each time the graph is loaded.
+#[tokio::test]
+async fn blank_node_diff() {
+    let repo_pool = create_repo_pool("blank-node-diff", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let mut graph = Graph {
+        id: "blank-nodes".to_string(),
+        graph: r#"
+        @prefix dct: <http://purl.org/dc/terms/> .
+
+        <https://example.com/dataset> dct:publisher _:b .
+        _:b dct:identifier "123" .
+        "#
+        .to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+    let original = graph.graph.clone();
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    std::thread::sleep(Duration::from_secs(1));
+    let first_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+    std::thread::sleep(Duration::from_secs(1));
+
+    graph.graph = original.replace("123", "124");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+    graph.graph = original;
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    let last_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+
+    // Stores are not cached, so both snapshots are loaded from the graph files.
+    let cache = QueryCache::with_budgets(CacheBudgets {
+        stores: 0,
+        ..CacheBudgets::default()
+    });
+    let (diff, _) = diff_with_cache(&repo, &cache, first_time, last_time)
+        .await
+        .expect("unable to diff graphs");
+    assert_eq!(diff.added, "");
+    assert_eq!(diff.removed, "");
+}
+
+/// Store graphs that share a triple, then remove it from one graph and add it to
+/// another, and check that the triple is not part of the diff, as it is in the
+/// snapshot at both timestamps.
+#[tokio::test]
+async fn diff_shared_triples() {
+    let repo_pool = create_repo_pool("diff-shared-triples", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let publisher = r#"<https://example.com/org> <http://xmlns.com/foaf/0.1/name> "Org" ."#;
+    let graph = |id: &str, title: &str, with_publisher: bool| Graph {
+        id: id.to_string(),
+        graph: format!(
+            "<https://example.com/{}> <http://purl.org/dc/terms/title> \"{}\" .\n{}",
+            id,
+            title,
+            if with_publisher { publisher } else { "" }
+        ),
+        format: Some("text/turtle".to_string()),
+    };
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graphs(
+        &repo,
+        &NoOpPrettifier::new(),
+        &[
+            graph("a", "A", true),
+            graph("b", "B", true),
+            graph("c", "C", false),
+        ],
+    )
+    .await
+    .expect("unable to store graphs");
+
+    std::thread::sleep(Duration::from_secs(1));
+    let mid_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+    std::thread::sleep(Duration::from_secs(1));
+
+    store_graphs(
+        &repo,
+        &NoOpPrettifier::new(),
+        &[graph("b", "B", false), graph("c", "C2", true)],
+    )
+    .await
+    .expect("unable to store graphs");
+
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+
+    let (diff, _) = diff_with_cache(&repo, &QueryCache::new(), mid_time, post_time)
+        .await
+        .expect("unable to diff graphs");
+    assert_eq!(
+        diff.added,
+        "<https://example.com/c> <http://purl.org/dc/terms/title> \"C2\" .\n"
+    );
+    assert_eq!(
+        diff.removed,
+        "<https://example.com/c> <http://purl.org/dc/terms/title> \"C\" .\n"
+    );
+}
+
+/// Graphs in any supported format should be stored, while unknown formats and
+/// invalid graphs should be rejected.
+#[tokio::test]
+async fn formats() {
+    let repo_pool = create_repo_pool("formats", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let mut graph = Graph {
+        id: "ntriples".to_string(),
+        graph:
+            "<https://www.w3schools00.com> <https://www.w3schools.com/rdf/title> \"W3Schools\" .\n"
+                .to_string(),
+        format: Some("application/n-triples".to_string()),
+    };
+
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    graph.format = Some("text/n3".to_string());
+    assert!(matches!(
+        store_graph(&repo, &NoOpPrettifier::new(), &graph).await,
+        Err(Error::BadRequest(_))
+    ));
+
+    graph.format = Some("text/turtle".to_string());
+    graph.graph = "<https://www.w3schools00.com> si:title \"W3Schools\" .".to_string();
+    assert!(matches!(
+        store_graph(&repo, &NoOpPrettifier::new(), &graph).await,
+        Err(Error::BadRequest(_))
+    ));
+
+    let commit_times = list_commit_times(&repo).expect("unable to list commits");
+    assert_eq!(commit_times.len(), 1);
+}
+
+/// JSON-LD should be sent to the prettifier API with its media type and stored as the
+/// Turtle returned, graphs the API can't parse should be rejected as invalid, and the
+/// local prettifier should reject JSON-LD as an unsupported media type.
+#[actix_web::test]
+async fn jsonld_graphs() {
+    let turtle = "<https://example.com/a> <http://purl.org/dc/terms/title> \"A\" .\n";
+    let server = HttpServer::new(move || {
+        App::new().route(
+            "/api/prettify",
+            web::post().to(move |body: web::Json<serde_json::Value>| async move {
+                match (body["format"].as_str(), body["output_format"].as_str()) {
+                    (Some("application/ld+json"), Some("text/turtle")) => {
+                        HttpResponse::Ok().body(turtle)
+                    }
+                    _ => HttpResponse::BadRequest().body("unable to parse graph"),
+                }
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .expect("unable to bind prettifier");
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    std::env::set_var(
+        "RDF_PRETTIFIER_URL",
+        format!("http://{}/api/prettify", address),
+    );
+    std::env::set_var("RDF_PRETTIFIER_API_KEY", "test");
+
+    let repo_pool = create_repo_pool("jsonld", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+
+    let mut graph = Graph {
+        id: "jsonld".to_string(),
+        graph: serde_json::json!({
+            "@id": "https://example.com/a",
+            "http://purl.org/dc/terms/title": "A",
+        })
+        .to_string(),
+        format: Some("application/ld+json".to_string()),
+    };
+    store_graph(&repo, &APIPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+    let (stored, _) = graph_at_timestamp(&repo, "jsonld", post_time, SnapshotFormat::Turtle)
+        .expect("unable to get graph");
+    assert_eq!(stored, turtle);
+
+    graph.graph = turtle.to_string();
+    graph.format = Some("application/n-triples".to_string());
+    let invalid = store_graph(&repo, &APIPrettifier::new(), &graph)
+        .await
+        .expect_err("stored graph the prettifier could not parse");
+    assert_eq!(invalid.status_code(), StatusCode::BAD_REQUEST);
+
+    let unsupported = LocalPrettifier::new()
+        .prettify("{}", RdfFormat::JsonLd)
+        .await
+        .expect_err("prettified JSON-LD locally");
+    assert_eq!(
+        unsupported.status_code(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+}
+
+/// The local prettifier should write the same graph identically, regardless of
+/// triple order and blank node labels.
+#[tokio::test]
+async fn local_prettifier() {
+    let graph_a = r#"
+        @prefix dct: <http://purl.org/dc/terms/> .
+
+        <https://example.com/dataset> dct:title "Dataset"@en ;
+            dct:publisher _:publisher .
+        _:publisher dct:identifier "123" .
+        "#;
+    let graph_b = r#"
+        _:b1 <http://purl.org/dc/terms/identifier> "123" .
+        <https://example.com/dataset> <http://purl.org/dc/terms/publisher> _:b1 .
+        <https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset"@en .
+        "#;
+
+    let prettifier = LocalPrettifier::new();
+    let prettified_a = prettifier
+        .prettify(graph_a, RdfFormat::Graph(GraphFormat::Turtle))
+        .await
+        .expect("unable to prettify graph");
+    let prettified_b = prettifier
+        .prettify(graph_b, RdfFormat::Graph(GraphFormat::NTriples))
+        .await
+        .expect("unable to prettify graph");
+
+    assert_eq!(prettified_a, prettified_b);
+    assert!(prettified_a.starts_with("@prefix dct: <http://purl.org/dc/terms/> .\n"));
+    validate_graph(&prettified_a, RdfFormat::Graph(GraphFormat::Turtle))
+        .expect("prettified graph is invalid");
+}
+
+/// Graphs with many indistinguishable blank nodes should fail to be canonicalized
+/// within the step budget instead of exhausting resources, as a client error when
+/// stored and as a server error when prettifying or diffing stored graphs.
+#[tokio::test]
+async fn canonicalization_limit() {
+    let distributions = ["[ a dcat:Distribution ]"; 10].join(", ");
+    let graph = format!(
+        r#"
+        @prefix dcat: <http://www.w3.org/ns/dcat#> .
+
+        <https://example.com/catalog> dcat:dataset _:a, _:b .
+        _:a dcat:distribution {distributions} .
+        _:b dcat:distribution {distributions} .
+        "#
+    );
+    let start_time = Instant::now();
+
+    let repo_pool = create_repo_pool("canonicalization-limit", 1).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    let invalid = store_graph(
+        &repo,
+        &LocalPrettifier::new(),
+        &Graph {
+            id: "blank-nodes".to_string(),
+            graph: graph.clone(),
+            format: Some("text/turtle".to_string()),
+        },
+    )
+    .await
+    .expect_err("stored graph");
+    assert_eq!(invalid.status_code(), StatusCode::BAD_REQUEST);
+
+    // Prettifying is also done when reading stored graphs.
+    let internal = LocalPrettifier::new()
+        .prettify(&graph, RdfFormat::Graph(GraphFormat::Turtle))
+        .await
+        .expect_err("canonicalized graph");
+    assert_eq!(internal.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let store = oxigraph::store::Store::new().expect("unable to create store");
+    store
+        .load_graph(
+            graph.as_bytes(),
+            GraphFormat::Turtle,
+            GraphNameRef::DefaultGraph,
+            None,
+        )
+        .expect("unable to load graph");
+    let empty = oxigraph::store::Store::new().expect("unable to create store");
+    let internal = diff_stores(&empty, &store).expect_err("diffed graph");
+    assert_eq!(internal.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    assert!(start_time.elapsed() < Duration::from_secs(60));
+}
+
+/// Store the same graph twice with different blank node labels and triple order,
+/// and check that only one commit is made.
+#[tokio::test]
+async fn blank_node_canonicalization() {
+    let repo_pool = create_repo_pool("blank-nodes", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let graph_a = Graph {
+        id: "blank-nodes".to_string(),
+        graph: r#"
+        @prefix dct: <http://purl.org/dc/terms/> .
+        @prefix foaf: <http://xmlns.com/foaf/0.1/> .
+
+        <https://example.com/dataset> dct:publisher _:a1 ;
+            dct:contributor _:a2, _:a3 .
+        _:a1 foaf:name "Publisher" .
+        _:a2 foaf:knows _:a3 .
+        _:a3 foaf:knows _:a2 .
+        "#
+        .to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+    let graph_b = Graph {
+        id: "blank-nodes".to_string(),
+        graph: r#"
+        @prefix dct: <http://purl.org/dc/terms/> .
+        @prefix foaf: <http://xmlns.com/foaf/0.1/> .
+
+        _:x foaf:knows _:y .
+        _:y foaf:knows _:x .
+        _:z foaf:name "Publisher" .
+        <https://example.com/dataset> dct:contributor _:y, _:x ;
+            dct:publisher _:z .
+        "#
+        .to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+
+    store_graph(&push_repo, &NoOpPrettifier::new(), &graph_a)
+        .await
+        .expect("unable to store graph");
+
+    store_graph(&push_repo, &NoOpPrettifier::new(), &graph_b)
+        .await
+        .expect("unable to store graph");
+
+    let commit_times = list_commit_times(&push_repo).expect("unable to list commits");
+    assert_eq!(commit_times.len(), 1);
+}
+
+/// Check that semantic comparison considers graphs equal regardless of
+/// formatting, triple order and blank node labels, while exact comparison does not.
+#[test]
+fn semantic_graph_comparison() {
+    let stored = r#"@prefix dct: <http://purl.org/dc/terms/> .
+
+<https://example.com/dataset> dct:publisher _:c14n0 ;
+    dct:title "Dataset" .
+
+_:c14n0 dct:identifier "123" .
+"#;
+    let incoming = r#"
+        _:b1 <http://purl.org/dc/terms/identifier> "123" .
+        <https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset" .
+        <https://example.com/dataset> <http://purl.org/dc/terms/publisher> _:b1 .
+        "#;
+    let changed = r#"
+        _:b1 <http://purl.org/dc/terms/identifier> "124" .
+        <https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset" .
+        <https://example.com/dataset> <http://purl.org/dc/terms/publisher> _:b1 .
+        "#;
+
+    assert!(GraphComparison::Semantic.equal(stored, incoming));
+    assert!(!GraphComparison::Semantic.equal(stored, changed));
+    assert!(!GraphComparison::Semantic.equal("invalid", incoming));
+    assert!(!GraphComparison::Exact.equal(stored, incoming));
+    assert!(GraphComparison::Exact.equal(stored, stored));
+}
+
+/// Store a batch of graphs, where one is invalid, and check that the valid
+/// graphs are stored in a single commit and that each graph gets a status.
+#[tokio::test]
+async fn batch() {
+    let repo_pool = create_repo_pool("batch", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let invalid = Graph {
+        id: "invalid".to_string(),
+        graph: "invalid graph".to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+
+    let statuses = store_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        &[graph("a"), invalid, graph("b")],
+    )
+    .await
+    .expect("unable to store graphs");
+    assert_eq!(
+        statuses.iter().map(|s| s.status).collect::<Vec<_>>(),
+        vec![Status::Changed, Status::Failed, Status::Changed]
+    );
+    assert!(statuses[1].error.is_some());
+
+    let statuses = store_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        &[graph("a"), titled_graph("b", "B2")],
+    )
+    .await
+    .expect("unable to store graphs");
+    assert_eq!(
+        statuses.iter().map(|s| s.status).collect::<Vec<_>>(),
+        vec![Status::Unchanged, Status::Changed]
+    );
+
+    let commit_times = list_commit_times(&push_repo).expect("unable to list commits");
+    assert_eq!(commit_times.len(), 2);
+
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs()
+        + 1;
+    let graphs = read_all_graph_files(&push_repo, post_time)
+        .await
+        .expect("unable to read graphs");
+    assert_eq!(graphs.len(), 2);
+}
+
+/// A write that fails to be committed should leave no changes in the working tree,
+/// so that storing the same graph again is committed instead of seen as unchanged.
+#[tokio::test]
+async fn failed_commit() {
+    let repo_pool = create_repo_pool("failed_commit", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    store_graph(&push_repo, &NoOpPrettifier::new(), &graph("a"))
+        .await
+        .expect("unable to store graph");
+
+    // Locking the branch makes the commit fail after the graph file is written.
+    let head = push_repo.head().expect("unable to get head");
+    let lock = push_repo
+        .path()
+        .join(format!("{}.lock", head.name().expect("invalid head")));
+    drop(head);
+    std::fs::write(&lock, "").expect("unable to lock branch");
+
+    store_graph(&push_repo, &NoOpPrettifier::new(), &graph("b"))
+        .await
+        .expect_err("stored graph");
+    store_graphs(&push_repo, &NoOpPrettifier::new(), &[graph("c")])
+        .await
+        .expect_err("stored graphs");
+    assert!(push_repo
+        .statuses(None)
+        .expect("unable to get statuses")
+        .is_empty());
+
+    std::fs::remove_file(&lock).expect("unable to unlock branch");
+    let statuses = store_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        &[graph("b"), graph("c")],
+    )
+    .await
+    .expect("unable to store graphs");
+    assert_eq!(
+        statuses.iter().map(|s| s.status).collect::<Vec<_>>(),
+        vec![Status::Changed, Status::Changed]
+    );
+    assert_eq!(
+        list_commit_times(&push_repo)
+            .expect("unable to list commits")
+            .len(),
+        2
+    );
+}
+
+/// Post a batch larger than the default payload limit of 256 KiB, and check that it
+/// is only rejected as too large without the payload config.
+#[actix_web::test]
+async fn large_batch() {
+    async fn store(
+        request: HttpRequest,
+        repos: web::Data<ReusableRepoPool>,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, Error> {
+        let graphs = parse_graphs(&request, &body)?;
+        let repo = repos.get().await?;
+        let statuses = store_graphs(&repo, &NoOpPrettifier::new(), &graphs).await?;
+        Ok(HttpResponse::Ok().json(statuses))
+    }
+
+    let repo_pool = create_repo_pool("large-batch", 1).await;
+    let graphs = (0..2000)
+        .map(|i| titled_graph(&i.to_string(), &"title ".repeat(20)))
+        .collect::<Vec<_>>();
+    let body = serde_json::to_vec(&graphs).expect("unable to serialize graphs");
+    assert!(body.len() > 256 * 1024);
+
+    let app = |payload_config: Option<web::PayloadConfig>| {
+        let app = App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .app_data(web::Data::clone(&repo_pool))
+            .route("/api/graphs/batch", web::post().to(store));
+        match payload_config {
+            Some(payload_config) => app.app_data(payload_config),
+            None => app,
+        }
+    };
+    let request = || {
+        actix_web::test::TestRequest::post()
+            .uri("/api/graphs/batch")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(body.clone())
+            .to_request()
+    };
+
+    let app_without_config = actix_web::test::init_service(app(None)).await;
+    let response = actix_web::test::call_service(&app_without_config, request()).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let app = actix_web::test::init_service(app(Some(payload_config()))).await;
+    let response = actix_web::test::call_service(&app, request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let statuses: Vec<models::GraphStatus> = actix_web::test::read_body_json(response).await;
+    assert_eq!(statuses.len(), graphs.len());
+    assert!(statuses
+        .iter()
+        .all(|status| status.status == Status::Changed));
+}
+
+/// Replace the graphs of a namespace, and check that missing graphs in the
+/// namespace are deleted in the same commit, while other graphs are kept.
+#[tokio::test]
+async fn replace() {
+    let repo_pool = create_repo_pool("replace", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    store_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        &[
+            graph("https://a.example.com/1"),
+            graph("https://a.example.com/2"),
+            graph("https://b.example.com/1"),
+        ],
+    )
+    .await
+    .expect("unable to store graphs");
+
+    let statuses = replace_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        "https://a.example.com/",
+        &[
+            graph("https://a.example.com/1"),
+            graph("https://a.example.com/3"),
+            graph("https://b.example.com/2"),
+        ],
+    )
+    .await
+    .expect("unable to replace graphs");
+    assert_eq!(
+        statuses
+            .iter()
+            .map(|s| (s.id.as_str(), s.status))
+            .collect::<Vec<_>>(),
+        vec![
+            ("https://a.example.com/1", Status::Unchanged),
+            ("https://a.example.com/3", Status::Changed),
+            ("https://b.example.com/2", Status::Failed),
+            ("https://a.example.com/2", Status::Deleted),
+        ]
+    );
+
+    let commit_times = list_commit_times(&push_repo).expect("unable to list commits");
+    assert_eq!(commit_times.len(), 2);
+
+    let history =
+        graph_history(&push_repo, "https://a.example.com/2").expect("unable to list history");
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].operation, Operation::Delete);
+
+    assert!(matches!(
+        replace_graphs(&push_repo, &NoOpPrettifier::new(), "", &[]).await,
+        Err(Error::BadRequest(_))
+    ));
+}
+
+/// Replace a namespace with an upload of newline delimited JSON larger than the
+/// default payload limit of 256 KiB, and check that it is accepted with the payload
+/// config.
+#[actix_web::test]
+async fn large_replace() {
+    async fn replace(
+        request: HttpRequest,
+        repos: web::Data<ReusableRepoPool>,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, Error> {
+        let graphs = parse_graphs(&request, &body)?;
+        let repo = repos.get().await?;
+        let statuses = replace_graphs(&repo, &NoOpPrettifier::new(), "ns/", &graphs).await?;
+        Ok(HttpResponse::Ok().json(statuses))
+    }
+
+    let repo_pool = create_repo_pool("large-replace", 1).await;
+    let mut body = Vec::new();
+    for i in 0..2000 {
+        let graph = titled_graph(&format!("ns/{}", i), &"title ".repeat(20));
+        serde_json::to_writer(&mut body, &graph).expect("unable to serialize graph");
+        body.push(b'\n');
+    }
+    assert!(body.len() > 256 * 1024);
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .app_data(payload_config())
+            .app_data(web::Data::clone(&repo_pool))
+            .route("/api/graphs/replace", web::put().to(replace)),
+    )
+    .await;
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::put()
+            .uri("/api/graphs/replace")
+            .insert_header((header::CONTENT_TYPE, "application/x-ndjson"))
+            .set_payload(body)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let statuses: Vec<models::GraphStatus> = actix_web::test::read_body_json(response).await;
+    assert_eq!(statuses.len(), 2000);
+    assert!(statuses
+        .iter()
+        .all(|status| status.status == Status::Changed));
+}
+
+/// A replace that fails to be committed should restore both written and removed
+/// graph files, so that the namespace can be replaced again.
+#[tokio::test]
+async fn failed_replace() {
+    let repo_pool = create_repo_pool("failed_replace", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    replace_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        "https://a.example.com/",
+        &[
+            graph("https://a.example.com/1"),
+            graph("https://a.example.com/2"),
+        ],
+    )
+    .await
+    .expect("unable to replace graphs");
+
+    // Locking the branch makes the commit fail after the graph files are changed.
+    let head = push_repo.head().expect("unable to get head");
+    let lock = push_repo
+        .path()
+        .join(format!("{}.lock", head.name().expect("invalid head")));
+    drop(head);
+    std::fs::write(&lock, "").expect("unable to lock branch");
+
+    let replacement = [graph("https://a.example.com/3")];
+    replace_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        "https://a.example.com/",
+        &replacement,
+    )
+    .await
+    .expect_err("replaced graphs");
+    assert!(push_repo
+        .statuses(None)
+        .expect("unable to get statuses")
+        .is_empty());
+
+    std::fs::remove_file(&lock).expect("unable to unlock branch");
+    let statuses = replace_graphs(
+        &push_repo,
+        &NoOpPrettifier::new(),
+        "https://a.example.com/",
+        &replacement,
+    )
+    .await
+    .expect("unable to replace graphs");
+    assert_eq!(
+        statuses.iter().map(|s| s.status).collect::<Vec<_>>(),
+        vec![Status::Changed, Status::Deleted, Status::Deleted]
+    );
+}
+
+/// Check that client errors are mapped to client error status codes and codes.
+#[tokio::test]
+async fn error_mapping() {
+    let repo_pool = create_repo_pool("errors", 2).await;
+    let push_repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let not_found = delete_graph(&push_repo, "missing".to_string())
+        .await
+        .expect_err("deleted missing graph");
+    assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+    assert_eq!(not_found.code(), "not_found");
+
+    let invalid_json = Error::from(serde_json::from_str::<Graph>("{").expect_err("parsed json"));
+    assert_eq!(invalid_json.status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(invalid_json.code(), "invalid_json");
+
+    let store = oxigraph::store::Store::new().expect("unable to create store");
+    let invalid_query = match store.query("SELEC *") {
+        Err(e) => Error::from(e),
+        Ok(_) => panic!("parsed invalid query"),
+    };
+    assert_eq!(invalid_query.status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(invalid_query.code(), "invalid_query");
+
+    let internal = Error::from("internal");
+    assert_eq!(internal.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(internal.code(), "internal_error");
+}
+
+/// Check that errors are returned as problem details, with position of SPARQL
+/// syntax errors and the request path as instance.
+#[actix_web::test]
+async fn problem_details() {
+    let store = oxigraph::store::Store::new().expect("unable to create store");
+    let problem = match store.query("SELECT *\nWHER { ?s ?p ?o }") {
+        Err(e) => Error::from(e).problem(),
+        Ok(_) => panic!("parsed invalid query"),
+    };
+    assert_eq!(problem.status, Some(400));
+    assert_eq!(problem.code.as_deref(), Some("invalid_query"));
+    // Fails if the position of syntax errors can no longer be read, e.g. because
+    // the peg dependency no longer matches the version used by spargebra.
+    let position = problem.position.expect("missing position");
+    assert_eq!(position.line, 2);
+    assert!(position.column >= 1);
+    assert_eq!(
+        position.offset,
+        "SELECT *\n".len() as i64 + position.column - 1
+    );
+
+    // Errors while evaluating a valid query are not the client's fault.
+    let service_query = "SELECT * WHERE { SERVICE <https://example.com/sparql> { ?s ?p ?o } }";
+    let evaluation_error = match store.query_opt(
+        service_query,
+        QueryOptions::default().without_service_handler(),
+    ) {
+        Ok(QueryResults::Solutions(mut solutions)) => {
+            solutions.find_map(Result::err).expect("evaluated service")
+        }
+        Ok(_) => panic!("unexpected query results"),
+        Err(e) => e,
+    };
+    let problem = Error::from(evaluation_error).problem();
+    assert_eq!(problem.status, Some(500));
+    assert_eq!(problem.code.as_deref(), Some("internal_error"));
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .route(
+                "/api/missing",
+                web::get().to(|| async {
+                    Err::<HttpResponse, Error>(Error::NotFound("missing".to_string()))
+                }),
+            ),
+    )
+    .await;
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::get()
+            .uri("/api/missing")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.as_bytes()),
+        Some(PROBLEM_JSON.as_bytes())
+    );
+
+    let problem: models::Error = actix_web::test::read_body_json(response).await;
+    assert_eq!(problem.status, Some(404));
+    assert_eq!(problem.title.as_deref(), Some("Not found"));
+    assert_eq!(problem.instance.as_deref(), Some("/api/missing"));
+}
+
+/// Check that requests rejected by extractors, for an invalid timestamp or a
+/// missing query parameter, are answered with problem details.
+#[actix_web::test]
+async fn extractor_problem_details() {
+    #[derive(serde::Deserialize)]
+    struct SparqlQueryParams {
+        #[allow(dead_code)]
+        query: String,
+    }
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .configure(extractor_config)
+            .route(
+                "/api/sparql/{timestamp}",
+                web::get().to(
+                    |_: web::Path<u64>, _: web::Query<SparqlQueryParams>| async {
+                        HttpResponse::Ok().finish()
+                    },
+                ),
+            ),
+    )
+    .await;
+
+    for uri in [
+        "/api/sparql/yesterday?query=ASK%20%7B%7D",
+        "/api/sparql/1700000000",
+    ] {
+        let response = actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri(uri).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|v| v.as_bytes()),
+            Some(PROBLEM_JSON.as_bytes())
+        );
+
+        let problem: models::Error = actix_web::test::read_body_json(response).await;
+        assert_eq!(problem.status, Some(400));
+        assert_eq!(problem.code.as_deref(), Some("bad_request"));
+        assert!(uri.starts_with(problem.instance.as_deref().expect("missing instance")));
+    }
+
+    // Other error responses get problem details from their status code.
+    let response = actix_web::test::call_service(
+        &app,
+        actix_web::test::TestRequest::post()
+            .uri("/api/sparql/1700000000")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let problem: models::Error = actix_web::test::read_body_json(response).await;
+    assert_eq!(problem.code.as_deref(), Some("method_not_allowed"));
+    assert_eq!(problem.title.as_deref(), Some("Method not allowed"));
+}
+
+/// Store a graph and query it with results in negotiated formats.
+#[tokio::test]
+async fn sparql_formats() {
+    let repo_pool = create_repo_pool("sparql-formats", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let graph = Graph {
+        id: "sparql".to_string(),
+        graph: r#"<https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset" ."#
+            .to_string(),
+        format: Some("text/turtle".to_string()),
+    };
+    store_graph(&repo, &NoOpPrettifier::new(), &graph)
+        .await
+        .expect("unable to store graph");
+
+    std::thread::sleep(Duration::from_secs(1));
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+
+    let cache = QueryCache::new();
+    let prettifier = NoOpPrettifier::new();
+    let query = |query: &str, formats: ResultFormats| {
+        query_with_cache(
+            &prettifier,
+            &repo,
+            &cache,
+            post_time,
+            query.to_string(),
+            formats,
+        )
+    };
+    let select = "SELECT ?title WHERE { ?s ?p ?title }";
+    let construct = "CONSTRUCT { ?s ?p ?o } WHERE { ?s ?p ?o }";
+
+    let (result, _) = query(select, ResultFormats::default())
+        .await
+        .expect("unable to query");
+    assert_eq!(result.media_type, "application/sparql-results+json");
+
+    let csv = ResultFormats::from_media_types(["text/csv", "application/n-triples"]);
+    let (result, _) = query(select, csv).await.expect("unable to query");
+    assert_eq!(result.media_type, "text/csv; charset=utf-8");
+    assert_eq!(result.body, "title\r\nDataset\r\n");
+
+    let (result, _) = query(construct, csv).await.expect("unable to query");
+    assert_eq!(result.media_type, "application/n-triples");
+    assert_eq!(
+        result.body,
+        "<https://example.com/dataset> <http://purl.org/dc/terms/title> \"Dataset\" .\n"
+    );
+
+    let turtle_only = ResultFormats::from_media_types(["text/turtle"]);
+    assert!(matches!(
+        query(select, turtle_only).await,
+        Err(Error::NotAcceptable(_))
+    ));
+
+    let any = ResultFormats::from_media_types(["application/xml", "*/*"]);
+    assert_eq!(any.solutions, Some(QueryResultsFormat::Xml));
+    assert_eq!(any.graph, Some(GraphFormat::RdfXml));
+}
+
+/// Store two graphs and get the snapshot in negotiated formats.
+#[tokio::test]
+async fn snapshot_formats() {
+    let repo_pool = create_repo_pool("snapshot-formats", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    for id in ["https://example.com/a", "b"] {
+        let graph = Graph {
+            id: id.to_string(),
+            graph:
+                r#"<https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset"@en ."#
+                    .to_string(),
+            format: Some("text/turtle".to_string()),
+        };
+        store_graph(&repo, &NoOpPrettifier::new(), &graph)
+            .await
+            .expect("unable to store graph");
+    }
+
+    std::thread::sleep(Duration::from_secs(1));
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+
+    assert_eq!(
+        SnapshotFormat::from_media_types(["application/json", "application/n-quads"]),
+        Some(SnapshotFormat::NQuads)
+    );
+    assert_eq!(SnapshotFormat::from_media_types(["application/json"]), None);
+    assert_eq!(
+        SnapshotFormat::from_media_types([]),
+        Some(SnapshotFormat::Turtle)
+    );
+
+    let cache = QueryCache::new();
+    let (nquads, _) = graphs_with_cache(
+        &NoOpPrettifier::new(),
+        &repo,
+        &cache,
+        post_time,
+        SnapshotFormat::NQuads,
+    )
+    .await
+    .expect("unable to get graphs");
+    let mut lines = nquads.lines().collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![
+            r#"<https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset"@en <https://example.com/a> ."#,
+            r#"<https://example.com/dataset> <http://purl.org/dc/terms/title> "Dataset"@en <urn:rdf-diff-store:graph:b> ."#,
+        ]
+    );
+
+    let (jsonld, _) = graphs_with_cache(
+        &NoOpPrettifier::new(),
+        &repo,
+        &cache,
+        post_time,
+        SnapshotFormat::JsonLd,
+    )
+    .await
+    .expect("unable to get graphs");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&jsonld).expect("invalid json"),
+        serde_json::json!([{
+            "@id": "https://example.com/dataset",
+            "http://purl.org/dc/terms/title": [{ "@value": "Dataset", "@language": "en" }],
+        }])
+    );
+
+    let (ntriples, _) = graph_at_timestamp(&repo, "b", post_time, SnapshotFormat::NTriples)
+        .expect("unable to get graph");
+    assert_eq!(
+        ntriples,
+        "<https://example.com/dataset> <http://purl.org/dc/terms/title> \"Dataset\"@en .\n"
+    );
+}
+
+/// Check that media types refused with q=0 are never negotiated, that wildcards
+/// of main types are matched, and that quoted triples are not acceptable as JSON-LD.
+#[tokio::test]
+async fn content_negotiation() {
+    let negotiate = |accept: &str| {
+        let request = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT, accept))
+            .to_http_request();
+        accepted_media_types(&request).and_then(|media_types| {
+            SnapshotFormat::from_media_types(media_types.iter().map(|m| m.essence_str()))
+                .ok_or_else(|| Error::NotAcceptable("not acceptable".to_string()))
+        })
+    };
+    assert_eq!(
+        negotiate("text/turtle;q=0, application/n-quads;q=0.5").ok(),
+        Some(SnapshotFormat::NQuads)
+    );
+    assert_eq!(
+        negotiate("application/*").ok(),
+        Some(SnapshotFormat::NTriples)
+    );
+    assert_eq!(
+        negotiate("text/turtle;q=0")
+            .expect_err("negotiated refused format")
+            .status_code(),
+        StatusCode::NOT_ACCEPTABLE
+    );
+    assert_eq!(
+        accepted_media_types(&actix_web::test::TestRequest::default().to_http_request())
+            .expect("unable to read accept header"),
+        Vec::<mime::Mime>::new()
+    );
+
+    let store = oxigraph::store::Store::new().expect("unable to create store");
+    let quoted = Triple::new(
+        NamedNode::new_unchecked("https://example.com/dataset"),
+        NamedNode::new_unchecked("http://purl.org/dc/terms/title"),
+        Literal::new_simple_literal("Dataset"),
+    );
+    store
+        .insert(&Quad::new(
+            quoted,
+            NamedNode::new_unchecked("http://purl.org/dc/terms/source"),
+            NamedNode::new_unchecked("https://example.com/catalog"),
+            GraphName::DefaultGraph,
+        ))
+        .expect("unable to insert quad");
+    assert_eq!(
+        serialize_store(&store, SnapshotFormat::JsonLd)
+            .expect_err("serialized quoted triple")
+            .status_code(),
+        StatusCode::NOT_ACCEPTABLE
+    );
+}
+
+/// Check that graph ids that are IRIs keep their id as graph name, and that ids
+/// that are not, or that look like encoded ids, never share a graph name.
+#[test]
+fn graph_names() {
+    assert_eq!(
+        graph_name("https://example.com/a").as_str(),
+        "https://example.com/a"
+    );
+    assert_eq!(graph_name("abc").as_str(), "urn:rdf-diff-store:graph:abc");
+    assert_eq!(
+        graph_name("urn:rdf-diff-store:graph:abc").as_str(),
+        "urn:rdf-diff-store:graph:urn%3Ardf-diff-store%3Agraph%3Aabc"
+    );
+}
+
+/// Store two graphs and check that each is queryable in its own named graph,
+/// with the default graph as their union.
+#[tokio::test]
+async fn named_graphs() {
+    let repo_pool = create_repo_pool("named-graphs", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    for (id, title) in [("https://example.com/a", "A"), ("b", "B")] {
+        let graph = Graph {
+            id: id.to_string(),
+            graph: format!(
+                r#"<https://example.com/{}> <http://purl.org/dc/terms/title> "{}" ."#,
+                title, title
+            ),
+            format: Some("text/turtle".to_string()),
+        };
+        store_graph(&repo, &NoOpPrettifier::new(), &graph)
+            .await
+            .expect("unable to store graph");
+    }
+
+    std::thread::sleep(Duration::from_secs(1));
+    let post_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+
+    let cache = QueryCache::new();
+    let csv = ResultFormats::from_media_types(["text/csv"]);
+
+    let (result, _) = query_with_cache(
+        &NoOpPrettifier::new(),
+        &repo,
+        &cache,
+        post_time,
+        "SELECT ?g ?title WHERE { GRAPH ?g { ?s ?p ?title } } ORDER BY ?title".to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(
+        result.body,
+        "g,title\r\nhttps://example.com/a,A\r\nurn:rdf-diff-store:graph:b,B\r\n"
+    );
+
+    let (result, _) = query_with_cache(
+        &NoOpPrettifier::new(),
+        &repo,
+        &cache,
+        post_time,
+        "SELECT (COUNT(*) AS ?count) WHERE { ?s ?p ?o }".to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(result.body, "count\r\n2\r\n");
+
+    // A later timestamp with the same head commit is served from the same cache entry.
+    let (_, cache_lvl) = query_with_cache(
+        &NoOpPrettifier::new(),
+        &repo,
+        &cache,
+        post_time + 60,
+        "SELECT (COUNT(*) AS ?count) WHERE { ?s ?p ?o }".to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert!(matches!(cache_lvl, CacheLevel::Query));
+}
+
+/// Build the store of a commit from the cached store of an earlier commit, and
+/// check that it matches a store loaded from scratch.
+#[tokio::test]
+async fn incremental_store() {
+    let repo_pool = create_repo_pool("incremental-store", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+
+    let prettifier = NoOpPrettifier::new();
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+    store_graph(&repo, &prettifier, &titled_graph("a", "A"))
+        .await
+        .expect("unable to store graph");
+    store_graph(&repo, &prettifier, &titled_graph("b", "B"))
+        .await
+        .expect("unable to store graph");
+    let first_time = timestamp_after_sleep();
+
+    store_graph(&repo, &prettifier, &titled_graph("a", "A2"))
+        .await
+        .expect("unable to store graph");
+    delete_graph(&repo, "b".to_string())
+        .await
+        .expect("unable to delete graph");
+    store_graph(&repo, &prettifier, &titled_graph("c", "C"))
+        .await
+        .expect("unable to store graph");
+    let second_time = timestamp_after_sleep();
+
+    let query = "SELECT ?g ?title WHERE { GRAPH ?g { ?s ?p ?title } } ORDER BY ?title";
+    let csv = ResultFormats::from_media_types(["text/csv"]);
+    let expected = "g,title\r\nurn:rdf-diff-store:graph:a,A2\r\nurn:rdf-diff-store:graph:c,C\r\n";
+
+    // Cache the store of the first commit, then build the second from it.
+    let cache = QueryCache::new();
+    let (result, _) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        first_time,
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(
+        result.body,
+        "g,title\r\nurn:rdf-diff-store:graph:a,A\r\nurn:rdf-diff-store:graph:b,B\r\n"
+    );
+
+    let (result, cache_lvl) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        second_time,
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(result.body, expected);
+    assert!(matches!(cache_lvl, CacheLevel::Incremental));
+
+    // The cached store of the first commit is left unchanged.
+    let (result, _) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        first_time,
+        "SELECT (COUNT(*) AS ?count) WHERE { ?s ?p ?o }".to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(result.body, "count\r\n2\r\n");
+
+    let (result, cache_lvl) = query_with_cache(
+        &prettifier,
+        &repo,
+        &QueryCache::new(),
+        second_time,
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(result.body, expected);
+    assert!(matches!(cache_lvl, CacheLevel::Nothing));
+}
+
+/// Persist stores on disk, check that they are reopened when used by a new cache,
+/// and that older stores are evicted when over the byte budget, but only removed
+/// from disk once no longer used.
+#[tokio::test]
+async fn persistent_stores() {
+    let repo_pool = create_repo_pool("persistent-stores", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+
+    let prettifier = NoOpPrettifier::new();
+    let mut timestamps = Vec::new();
+    for title in ["A", "B"] {
+        store_graph(&repo, &prettifier, &titled_graph("a", title))
+            .await
+            .expect("unable to store graph");
+        timestamps.push(timestamp_after_sleep());
+    }
+    let commits = list_commit_times(&repo)
+        .expect("unable to list commits")
+        .into_iter()
+        .map(|(_, oid)| oid)
+        .collect::<Vec<_>>();
+
+    let path = format!(
+        "./tmp-repos/persistent-stores-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time err")
+            .as_nanos()
+    );
+    let query = "SELECT ?title WHERE { ?s ?p ?title }";
+    let csv = ResultFormats::from_media_types(["text/csv"]);
+
+    let cache = QueryCache::with_persistent_stores(PersistentStores::new(&path, u64::MAX))
+        .expect("unable to open persistent stores");
+    query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        timestamps[0],
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    drop(cache);
+    assert!(std::path::Path::new(&path)
+        .join(commits[0].to_string())
+        .is_dir());
+
+    // A new cache reopens the persisted store, and only has to run the query.
+    let cache = QueryCache::with_persistent_stores(PersistentStores::new(&path, 1))
+        .expect("unable to open persistent stores");
+    let (result, cache_lvl) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        timestamps[0],
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert!(matches!(cache_lvl, CacheLevel::Graph));
+    assert_eq!(result.body, "title\r\nA\r\n");
+
+    // Persisting the store of the next commit evicts the first one, as both don't fit.
+    let reader = cache
+        .store_cache
+        .get(&Some(commits[0]))
+        .expect("store not cached");
+    let (result, _) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        timestamps[1],
+        query.to_string(),
+        csv,
+    )
+    .await
+    .expect("unable to query");
+    assert_eq!(result.body, "title\r\nB\r\n");
+    assert!(std::path::Path::new(&path)
+        .join(commits[1].to_string())
+        .is_dir());
+    assert!(!cache.store_cache.contains_key(&Some(commits[0])));
+
+    // The evicted store is still readable until its last reader is done with it.
+    assert_eq!(reader.len().expect("unable to read store"), 1);
+    assert!(std::path::Path::new(&path)
+        .join(commits[0].to_string())
+        .is_dir());
+    drop(reader);
+    cache.store_cache.run_pending_tasks();
+    assert!(!std::path::Path::new(&path)
+        .join(commits[0].to_string())
+        .exists());
+}
+
+/// Check that cache entries over budget are evicted and counted, and that entries
+/// expire when not accessed.
+#[tokio::test]
+async fn cache_budgets() {
+    let repo_pool = create_repo_pool("cache-budgets", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+
+    let prettifier = NoOpPrettifier::new();
+    store_graph(&repo, &prettifier, &graph("a"))
+        .await
+        .expect("unable to store graph");
+    let timestamp = timestamp_after_sleep();
+
+    let cache = QueryCache::with_budgets(CacheBudgets {
+        stores: 1024 * 1024,
+        graphs: 1024 * 1024,
+        formats: 1024 * 1024,
+        queries: 1,
+        time_to_idle: Duration::from_secs(2),
+    });
+    let evictions = || {
+        CACHE_COUNT
+            .with_label_values(&["queries", "evicted_too_large"])
+            .get()
+    };
+    let evictions_before = evictions();
+
+    // Query results don't fit in the query cache, so the query is run again.
+    for _ in 0..2 {
+        let (_, cache_lvl) = query_with_cache(
+            &prettifier,
+            &repo,
+            &cache,
+            timestamp,
+            "SELECT * WHERE { ?s ?p ?o }".to_string(),
+            ResultFormats::default(),
+        )
+        .await
+        .expect("unable to query");
+        cache.query_cache.run_pending_tasks();
+        assert!(!matches!(cache_lvl, CacheLevel::Query));
+    }
+    assert!(evictions() > evictions_before);
+
+    let commit = commit_at_timestamp(&repo, timestamp).expect("unable to get commit");
+    cache.store_cache.run_pending_tasks();
+    assert!(cache.store_cache.contains_key(&commit));
+    assert!(cache
+        .sizes()
+        .iter()
+        .any(|(cache_type, _, bytes)| *cache_type == "stores" && *bytes > 0));
+
+    std::thread::sleep(Duration::from_secs(3));
+    cache.store_cache.run_pending_tasks();
+    assert!(!cache.store_cache.contains_key(&commit));
+}
+
+/// Warm up cache, and check that the latest store, stores at given timestamps and
+/// results of given queries are cached.
+#[tokio::test]
+async fn cache_warm_up() {
+    let repo_pool = create_repo_pool("cache-warm-up", 2).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    checkout_main_and_fetch_updates(&repo).expect("unable to checkout main and fetch");
+
+    let prettifier = NoOpPrettifier::new();
+    let mut timestamps = Vec::new();
+    for id in ["a", "b"] {
+        store_graph(&repo, &prettifier, &graph(id))
+            .await
+            .expect("unable to store graph");
+        timestamps.push(timestamp_after_sleep());
+    }
+
+    let query = "SELECT (COUNT(*) AS ?count) WHERE { ?s ?p ?o }".to_string();
+    let cache = QueryCache::new();
+    warm_up(
+        &prettifier,
+        &repo,
+        &cache,
+        &timestamps[..1],
+        std::slice::from_ref(&query),
+    )
+    .await
+    .expect("unable to warm up cache");
+
+    for timestamp in &timestamps {
+        let commit = commit_at_timestamp(&repo, *timestamp).expect("unable to get commit");
+        assert!(cache.store_cache.contains_key(&commit));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+    let (_, cache_lvl) = query_with_cache(
+        &prettifier,
+        &repo,
+        &cache,
+        now,
+        query,
+        ResultFormats::default(),
+    )
+    .await
+    .expect("unable to query");
+    assert!(matches!(cache_lvl, CacheLevel::Query));
+}
+
+/// Check that repos are handed out in the order requested, that they are put back in
+/// pool when dropped, even on early returns, and that waiting for a repo times out.
+#[tokio::test]
+async fn repo_pool() {
+    let repo_pool = new_repo_pool("repo-pool", 1)
+        .await
+        .with_timeout(Duration::from_millis(500));
+
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    match repo_pool.get().await {
+        Err(e) => assert_eq!(e.status_code(), StatusCode::SERVICE_UNAVAILABLE),
+        Ok(_) => panic!("got repo from empty pool"),
+    }
+    drop(repo);
+
+    async fn failing_handler(repo_pool: &ReusableRepoPool) -> Result<(), Error> {
+        let repo = repo_pool.get().await?;
+        checkout_main_and_fetch_updates(&repo)?;
+        delete_graph(&repo, "missing".to_string()).await?;
+        Ok(())
+    }
+    assert!(failing_handler(&repo_pool).await.is_err());
+
+    // Waiting requests get the repo in the order they asked for it.
+    let order = std::cell::RefCell::new(Vec::new());
+    let repo = repo_pool.get().await.expect("repo not put back in pool");
+    let waiting = |i| {
+        let repo_pool = &repo_pool;
+        let order = &order;
+        async move {
+            let _repo = repo_pool.get().await.expect("unable to get repo from pool");
+            order.borrow_mut().push(i);
+        }
+    };
+    tokio::join!(waiting(1), waiting(2), waiting(3), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(repo);
+    });
+    assert_eq!(order.into_inner(), vec![1, 2, 3]);
+}
+
+/// Push a graph from another clone, and check that the background fetch moves main
+/// to it and warms up caches for the new head.
+#[actix_web::test]
+async fn background_fetch() {
+    let repo_pool = create_repo_pool("background-fetch", 2).await;
+    let push_pool = clone_repo_pool(&repo_pool, "background-fetch-push").await;
+    let state = web::Data::new(State {
+        cache: QueryCache::new(),
+        rdf_prettifier: ConfiguredPrettifier::new(),
+    });
+    let fetch = fetch_in_background(
+        web::Data::clone(&repo_pool),
+        web::Data::clone(&state),
+        Duration::from_millis(100),
+    );
+
+    let head = push_graph(&push_pool, "a").await;
+    wait_until(|| state.cache.store_cache.contains_key(&Some(head))).await;
+    let repo = repo_pool.get().await.expect("unable to get repo from pool");
+    assert_eq!(repo.refname_to_id("refs/heads/main").ok(), Some(head));
+    drop(repo);
+
+    // Later updates are fetched too.
+    let head = push_graph(&push_pool, "b").await;
+    wait_until(|| state.cache.store_cache.contains_key(&Some(head))).await;
+
+    fetch.abort();
+}
+
+/// Push a graph from another clone, and check that refresh moves main to it and
+/// warms up caches for the new head.
+#[actix_web::test]
+async fn refresh() {
+    let repo_pool = create_repo_pool("refresh", 2).await;
+    let push_pool = clone_repo_pool(&repo_pool, "refresh-push").await;
+    let state = web::Data::new(State {
+        cache: QueryCache::new(),
+        rdf_prettifier: ConfiguredPrettifier::new(),
+    });
+    let app = actix_web::test::init_service(
+        App::new()
+            .wrap(ErrorHandlers::new().default_handler(problem_instance))
+            .app_data(web::Data::clone(&repo_pool))
+            .app_data(web::Data::clone(&state))
+            .service(post_api_refresh),
+    )
+    .await;
+    let refresh = |api_key: Option<String>| {
+        let request = actix_web::test::TestRequest::post().uri("/api/refresh");
+        match api_key {
+            Some(api_key) => request.insert_header(("X-API-KEY", api_key)),
+            None => request,
+        }
+        .to_request()
+    };
+
+    let response = actix_web::test::call_service(&app, refresh(None)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let head = push_graph(&push_pool, "a").await;
+    let response = actix_web::test::call_service(&app, refresh(Some(api_key()))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: models::Refresh = actix_web::test::read_body_json(response).await;
+    assert!(body.updated);
+    assert_eq!(body.head, Some(head.to_string()));
+    wait_until(|| state.cache.store_cache.contains_key(&Some(head))).await;
 
-    ReusableRepoPool::push(&repo_pool, push_repo).await;
+    // Main is already up to date.
+    let response = actix_web::test::call_service(&app, refresh(Some(api_key()))).await;
+    let body: models::Refresh = actix_web::test::read_body_json(response).await;
+    assert!(!body.updated);
+    assert_eq!(body.head, Some(head.to_string()));
 }
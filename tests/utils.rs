@@ -1,11 +1,17 @@
 use std::{
     env,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use actix_web::web;
 use async_trait::async_trait;
-use rdf_diff_store::{error::Error, git::ReusableRepoPool, rdf::RdfPrettifier};
+use rdf_diff_store::{
+    error::Error,
+    git::{checkout_main_and_fetch_updates, push_updates, ReusableRepoPool},
+    graphs::store_graph,
+    models::Graph,
+    rdf::{RdfFormat, RdfPrettifier},
+};
 
 use lazy_static::lazy_static;
 use reqwest::{header, StatusCode};
@@ -24,15 +30,16 @@ impl RdfPrettifier for NoOpPrettifier {
         NoOpPrettifier {}
     }
 
-    async fn prettify(&self, graph: &str) -> Result<String, Error> {
+    async fn prettify(&self, graph: &str, _format: RdfFormat) -> Result<String, Error> {
         Ok(graph.to_string())
     }
 }
 
-pub async fn create_repo_pool(
-    name: &'static str,
-    size: u64,
-) -> web::Data<async_lock::Mutex<ReusableRepoPool>> {
+pub async fn create_repo_pool(name: &'static str, size: u64) -> web::Data<ReusableRepoPool> {
+    web::Data::new(new_repo_pool(name, size).await)
+}
+
+pub async fn new_repo_pool(name: &'static str, size: u64) -> ReusableRepoPool {
     let time = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("time err")
@@ -45,14 +52,97 @@ pub async fn create_repo_pool(
         .await
         .expect("unable to create gitea repo");
 
-    web::Data::new(async_lock::Mutex::new(
-        ReusableRepoPool::new(
-            format!("{}/gitea/{}.git", GIT_REPO_BASE_URL.clone(), timed_name),
-            format!("./tmp-repos/{}", timed_name),
-            size,
-        )
-        .expect("unable to create repo pool"),
-    ))
+    ReusableRepoPool::new(
+        format!("{}/gitea/{}.git", GIT_REPO_BASE_URL.clone(), timed_name),
+        format!("./tmp-repos/{}", timed_name),
+        size,
+    )
+    .expect("unable to create repo pool")
+}
+
+/// Another clone of the gitea repo of a pool, to push updates that the pool has
+/// to fetch.
+pub async fn clone_repo_pool(repo_pool: &ReusableRepoPool, name: &'static str) -> ReusableRepoPool {
+    let url = repo_pool
+        .get()
+        .await
+        .expect("unable to get repo from pool")
+        .find_remote("origin")
+        .expect("unable to find origin")
+        .url()
+        .expect("invalid origin url")
+        .to_string();
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+
+    ReusableRepoPool::new(url, format!("./tmp-repos/{}-{}", time, name), 1)
+        .expect("unable to create repo pool")
+}
+
+/// Push a graph from a clone, and return the new head of main.
+pub async fn push_graph(push_pool: &ReusableRepoPool, id: &str) -> git2::Oid {
+    let push_repo = push_pool.get().await.expect("unable to get repo from pool");
+    checkout_main_and_fetch_updates(&push_repo).expect("unable to checkout main and fetch");
+    store_graph(&push_repo, &NoOpPrettifier::new(), &graph(id))
+        .await
+        .expect("unable to store graph");
+    push_updates(&push_repo).expect("unable to push");
+    push_repo
+        .refname_to_id("refs/heads/main")
+        .expect("unable to get main")
+}
+
+/// API key to send to endpoints, set if not configured.
+pub fn api_key() -> String {
+    env::var("API_KEY").unwrap_or_else(|_| {
+        env::set_var("API_KEY", "test");
+        "test".to_string()
+    })
+}
+
+/// Wait for condition to hold, failing after 10 seconds.
+pub async fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "timed out waiting for condition"
+        );
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Turtle graph titled by its id.
+pub fn graph(id: &str) -> Graph {
+    titled_graph(id, id)
+}
+
+/// Turtle graph with a single title triple, so that graphs can be changed by
+/// changing their title.
+pub fn titled_graph(id: &str, title: &str) -> Graph {
+    Graph {
+        id: id.to_string(),
+        graph: format!(
+            "<https://example.com/{}> <http://purl.org/dc/terms/title> \"{}\" .\n",
+            id, title
+        ),
+        format: Some("text/turtle".to_string()),
+    }
+}
+
+/// Current time, with a second of sleep before and after, so that it falls strictly
+/// between commits made before and after.
+pub fn timestamp_after_sleep() -> u64 {
+    std::thread::sleep(Duration::from_secs(1));
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time err")
+        .as_secs();
+    std::thread::sleep(Duration::from_secs(1));
+    timestamp
 }
 
 async fn create_gitea_repo(name: &String) -> Result<(), Error> {